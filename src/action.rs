@@ -0,0 +1,110 @@
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+use std::any::Any;
+
+use crate::device::{DeviceActionTrait, DeviceError};
+
+/// Action of any device type, routed by the controller to a device by id
+pub struct DeviceNodeAction(Box<dyn DeviceActionTrait>);
+
+impl DeviceNodeAction {
+    pub fn new<A: DeviceActionTrait>(action: A) -> DeviceNodeAction {
+        DeviceNodeAction(Box::new(action))
+    }
+
+    /// The action, if it is of the given type
+    pub fn downcast_ref<A: DeviceActionTrait>(&self) -> Option<&A> {
+        let action: &dyn Any = self.0.as_ref();
+        action.downcast_ref()
+    }
+
+    /// Name and arguments, see `DeviceActionTrait::describe`
+    pub fn describe(&self) -> (&'static str, Value) {
+        self.0.describe()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ArgKind {
+    Bool,
+    Integer { min: i64, max: i64 },
+    String,
+    Enum { values: &'static [&'static str] },
+    Json, // free-form object, e.g. a schedule
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ArgSpec {
+    pub name: &'static str,
+    #[serde(flatten)]
+    pub kind: ArgKind,
+    pub optional: bool,
+}
+
+/// Action accepted by name, as listed to the API clients
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ActionSpec {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub args: Vec<ArgSpec>,
+}
+
+impl ActionSpec {
+    pub fn new(name: &'static str, description: &'static str) -> ActionSpec {
+        ActionSpec {
+            name,
+            description,
+            args: Vec::new(),
+        }
+    }
+
+    pub fn arg(mut self, name: &'static str, kind: ArgKind) -> ActionSpec {
+        self.args.push(ArgSpec {
+            name,
+            kind,
+            optional: false,
+        });
+        self
+    }
+
+    pub fn optional(mut self, name: &'static str, kind: ArgKind) -> ActionSpec {
+        self.args.push(ArgSpec {
+            name,
+            kind,
+            optional: true,
+        });
+        self
+    }
+}
+
+/// Arguments of a named action, a JSON object
+#[derive(Debug, Default)]
+pub struct ActionArgs(Map<String, Value>);
+
+impl ActionArgs {
+    pub fn new(args: Value) -> Result<ActionArgs, DeviceError> {
+        match args {
+            Value::Null => Ok(ActionArgs::default()),
+            Value::Object(args) => Ok(ActionArgs(args)),
+            _ => Err(DeviceError::InvalidArgs),
+        }
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, name: &str) -> Result<T, DeviceError> {
+        self.get_opt(name)?.ok_or(DeviceError::InvalidArgs)
+    }
+
+    /// Missing and null arguments are both None
+    pub fn get_opt<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, DeviceError> {
+        match self.0.get(name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => T::deserialize(value)
+                .map(Some)
+                .map_err(|_| DeviceError::InvalidArgs),
+        }
+    }
+}
@@ -1,32 +1,572 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
 use crate::{
+    action::{ActionArgs, ActionSpec, ArgKind},
     can::CanFrame,
     controller::ControllerAPI,
-    device::{DeviceActionTrait, DeviceControllableTrait, DeviceError, DeviceTrait},
+    device::{DeviceActionTrait, DeviceControllableTrait, DeviceError, DeviceTrait, NodeSnapshot},
+    event::DeviceEventKind,
+    registry::DevicePlugin,
+    secret::verify_secret,
 };
 
+// Byte 0 of the frames sent by the alarm node, byte 1 holds the zones bitmask
+const FRAME_TRIGGER: u8 = 0x01;
+
+// Command sent to the alarm node on every state transition:
+// [CMD_SET_STATE, state, triggered zones, bypassed zones, 0, 0, 0, 0]
+const CMD_SET_STATE: u8 = 0x10;
+
+// Light control command: [CMD_LIGHTS, lights, 0, 0, 0, 0, 0, 0]
+// acknowledged by the node with [CMD_LIGHTS | ACK, lights, ...]
+const CMD_LIGHTS: u8 = 0x20;
+const LIGHT_FRONT: u8 = 0x01;
+const LIGHT_REAR: u8 = 0x02;
+const ACK: u8 = 0x80;
+const ACK_TIMEOUT: Duration = Duration::from_millis(1000);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AlarmConfig {
+    pub exit_delay: u32,    // in seconds
+    pub entry_delay: u32,   // in seconds
+    pub entry_zones: u8,    // zones starting the entry delay rather than triggering
+    pub interior_zones: u8, // zones ignored when armed in stay mode
+
+    // User codes, hashed (see `secret::hash_secret`). When any is set, arming,
+    // disarming and bypassing require a code.
+    pub user_codes: Vec<String>,
+    pub duress_codes: Vec<String>, // disarm, but raise a silent event
+    pub quick_arm: bool,           // allow arming without a code
+    pub max_attempts: u32,         // failed attempts before lockout
+    pub lockout: u32,              // in seconds
+
+    pub trigger_lights: u32, // lights on for this many seconds on trigger, 0 to disable
+}
+
+impl Default for AlarmConfig {
+    fn default() -> AlarmConfig {
+        AlarmConfig {
+            exit_delay: 30,
+            entry_delay: 30,
+            entry_zones: 0x01,
+            interior_zones: 0x00,
+            user_codes: Vec::new(),
+            duress_codes: Vec::new(),
+            quick_arm: false,
+            max_attempts: 3,
+            lockout: 300,
+            trigger_lights: 300,
+        }
+    }
+}
+
+impl AlarmConfig {
+    fn codes_required(&self) -> bool {
+        !self.user_codes.is_empty() || !self.duress_codes.is_empty()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum CodeKind {
+    User,
+    Duress,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ArmMode {
+    #[default]
+    Away,
+    Stay,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmState {
+    #[default]
+    Disarmed,
+    ArmingExit,
+    ArmedAway,
+    ArmedStay,
+    EntryDelay,
+    Triggered,
+}
+
+impl AlarmState {
+    /// Encoding of the state in the `CMD_SET_STATE` command
+    fn code(&self) -> u8 {
+        match self {
+            AlarmState::Disarmed => 0,
+            AlarmState::ArmingExit => 1,
+            AlarmState::ArmedAway => 2,
+            AlarmState::ArmedStay => 3,
+            AlarmState::EntryDelay => 4,
+            AlarmState::Triggered => 5,
+        }
+    }
+
+    pub fn is_armed(&self) -> bool {
+        *self != AlarmState::Disarmed
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Lights {
+    pub front: bool,
+    pub rear: bool,
+}
+
+impl Lights {
+    pub fn new(front: bool, rear: bool) -> Lights {
+        Lights { front, rear }
+    }
+
+    fn encode(&self) -> u8 {
+        (if self.front { LIGHT_FRONT } else { 0 }) | (if self.rear { LIGHT_REAR } else { 0 })
+    }
+
+    fn decode(bits: u8) -> Lights {
+        Lights {
+            front: bits & LIGHT_FRONT != 0,
+            rear: bits & LIGHT_REAR != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct AlarmSnapshot {
+    pub state: AlarmState,
+    pub mode: ArmMode,
+    pub bypassed_zones: u8,
+    pub triggered_zones: u8,
+    pub triggered_count: u32,
+    pub lights: Lights,
+    pub lights_off_in_secs: Option<u64>,
+}
+
 #[derive(Debug, Default)]
 pub struct AlarmNode {
-    pub active: bool,
+    pub config: AlarmConfig,
+    pub state: AlarmState,
+    pub mode: ArmMode,
+    pub bypassed_zones: u8,
+    pub triggered_zones: u8,
     pub triggered_count: u32,
+    pub lights: Lights, // as acknowledged by the node
+
+    deadline: Option<Instant>, // end of the running exit or entry delay
+    lights_off_at: Option<Instant>,
+    failed_attempts: u32,
+    locked_until: Option<Instant>,
+    events: Vec<DeviceEventKind>,
+}
+
+impl AlarmNode {
+    pub fn new(config: AlarmConfig) -> AlarmNode {
+        AlarmNode {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Validate a user code, applying the lockout policy
+    fn check_code(&mut self, code: &str) -> Result<CodeKind, DeviceError> {
+        if let Some(until) = self.locked_until {
+            if Instant::now() < until {
+                return Err(DeviceError::LockedOut);
+            }
+            self.locked_until = None;
+        }
+
+        let kind = if self
+            .config
+            .duress_codes
+            .iter()
+            .any(|h| verify_secret(h, code))
+        {
+            Some(CodeKind::Duress)
+        } else if self
+            .config
+            .user_codes
+            .iter()
+            .any(|h| verify_secret(h, code))
+        {
+            Some(CodeKind::User)
+        } else {
+            None
+        };
+
+        let Some(kind) = kind else {
+            self.failed_attempts += 1;
+            self.events.push(DeviceEventKind::AlarmCodeRejected {
+                attempts: self.failed_attempts,
+            });
+            if self.failed_attempts >= self.config.max_attempts {
+                self.failed_attempts = 0;
+                self.locked_until =
+                    Some(Instant::now() + Duration::from_secs(self.config.lockout as u64));
+                self.events.push(DeviceEventKind::AlarmLockedOut);
+            }
+            return Err(DeviceError::InvalidCode);
+        };
+
+        self.failed_attempts = 0;
+        Ok(kind)
+    }
+
+    /// Whether the action needs a code, once codes are configured
+    fn is_protected(&self, action: &AlarmAction) -> bool {
+        match action {
+            AlarmAction::SetActive(true) | AlarmAction::Arm(_) => !self.config.quick_arm,
+            AlarmAction::SetActive(false) | AlarmAction::Disarm | AlarmAction::Bypass(_) => true,
+            AlarmAction::PowerLights(..) | AlarmAction::PowerLightsFor(..) => false,
+            AlarmAction::WithCode(..) => true,
+        }
+    }
+
+    async fn run_action(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        action: &AlarmAction,
+    ) -> Result<(), DeviceError> {
+        match action {
+            AlarmAction::SetActive(true) => {
+                self.arm(api, ArmMode::Away).await?;
+            }
+            AlarmAction::SetActive(false) | AlarmAction::Disarm => {
+                self.disarm(api).await;
+            }
+            AlarmAction::Arm(mode) => {
+                self.arm(api, *mode).await?;
+            }
+            AlarmAction::Bypass(zones) => {
+                if self.state != AlarmState::Disarmed {
+                    return Err(DeviceError::InvalidState);
+                }
+                self.bypassed_zones = *zones;
+            }
+            AlarmAction::PowerLights(front, rear) => {
+                self.lights_off_at = None;
+                self.set_lights(api, Lights::new(*front, *rear)).await?;
+            }
+            AlarmAction::PowerLightsFor(front, rear, secs) => {
+                self.power_lights_for(api, Lights::new(*front, *rear), *secs)
+                    .await?;
+            }
+            // codes are not nested
+            AlarmAction::WithCode(..) => return Err(DeviceError::Unsupported),
+        };
+
+        Ok(())
+    }
+
+    async fn set_lights(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        lights: Lights,
+    ) -> Result<(), DeviceError> {
+        let is_ack = |frame: &CanFrame| frame.data[0] == CMD_LIGHTS | ACK;
+        let ack = api
+            .request(
+                [CMD_LIGHTS, lights.encode(), 0, 0, 0, 0, 0, 0],
+                &is_ack,
+                ACK_TIMEOUT,
+            )
+            .await?;
+
+        let lights = Lights::decode(ack.data[1]);
+        if lights != self.lights {
+            self.lights = lights;
+            self.events.push(DeviceEventKind::AlarmLightsChanged {
+                front: lights.front,
+                rear: lights.rear,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Power the lights on, and off again after `secs` seconds
+    async fn power_lights_for(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        lights: Lights,
+        secs: u32,
+    ) -> Result<(), DeviceError> {
+        self.set_lights(api, lights).await?;
+        self.lights_off_at = Some(Instant::now() + Duration::from_secs(secs as u64));
+
+        Ok(())
+    }
+
+    async fn transition(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        state: AlarmState,
+        delay: Option<u32>,
+    ) {
+        self.deadline = delay.map(|secs| Instant::now() + Duration::from_secs(secs as u64));
+
+        if self.state == state {
+            return;
+        }
+        self.state = state;
+
+        api.command([
+            CMD_SET_STATE,
+            state.code(),
+            self.triggered_zones,
+            self.bypassed_zones,
+            0,
+            0,
+            0,
+            0,
+        ])
+        .await;
+        self.events
+            .push(DeviceEventKind::AlarmStateChanged { state });
+
+        if state == AlarmState::Triggered && self.config.trigger_lights > 0 {
+            let secs = self.config.trigger_lights;
+            if let Err(err) = self
+                .power_lights_for(api, Lights::new(true, true), secs)
+                .await
+            {
+                println!("Failed to power the lights on trigger: {}", err);
+            }
+        }
+    }
+
+    async fn arm(&mut self, api: &mut dyn ControllerAPI, mode: ArmMode) -> Result<(), DeviceError> {
+        if self.state != AlarmState::Disarmed {
+            return Err(DeviceError::InvalidState);
+        }
+
+        self.mode = mode;
+        self.triggered_zones = 0;
+        let delay = self.config.exit_delay;
+        self.transition(api, AlarmState::ArmingExit, Some(delay))
+            .await;
+
+        Ok(())
+    }
+
+    async fn disarm(&mut self, api: &mut dyn ControllerAPI) {
+        self.bypassed_zones = 0;
+        self.transition(api, AlarmState::Disarmed, None).await;
+    }
+
+    async fn trigger(&mut self, api: &mut dyn ControllerAPI, zones: u8) {
+        let mut zones = zones & !self.bypassed_zones;
+        if self.state == AlarmState::ArmedStay {
+            zones &= !self.config.interior_zones;
+        }
+
+        let armed = matches!(
+            self.state,
+            AlarmState::ArmedAway | AlarmState::ArmedStay | AlarmState::EntryDelay
+        );
+        if !armed || zones == 0 {
+            return;
+        }
+
+        self.triggered_count += 1;
+        self.triggered_zones |= zones;
+        self.events.push(DeviceEventKind::AlarmTriggered { zones });
+
+        if self.state == AlarmState::EntryDelay {
+            // entry delay already running, only the other zones trigger
+            if zones & !self.config.entry_zones != 0 {
+                self.transition(api, AlarmState::Triggered, None).await;
+            }
+        } else if zones & !self.config.entry_zones == 0 {
+            let delay = self.config.entry_delay;
+            self.transition(api, AlarmState::EntryDelay, Some(delay))
+                .await;
+        } else {
+            self.transition(api, AlarmState::Triggered, None).await;
+        }
+    }
 }
 
 #[async_trait]
 impl DeviceTrait for AlarmNode {
-    async fn handle_frame(&mut self, frame: &CanFrame) -> Result<(), DeviceError> {
-        if frame.data[0] & 0x01 != 0 {
-            self.triggered_count += 1;
+    async fn handle_frame(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        frame: &CanFrame,
+    ) -> Result<(), DeviceError> {
+        if frame.data[0] == FRAME_TRIGGER {
+            // nodes without zone support report the trigger on zone 1
+            let zones = match frame.data[1] {
+                0 => 0x01,
+                zones => zones,
+            };
+            self.trigger(api, zones).await;
         }
 
         Ok(())
     }
+
+    async fn tick(&mut self, api: &mut dyn ControllerAPI) -> Result<(), DeviceError> {
+        if self.lights_off_at.is_some_and(|at| Instant::now() >= at) {
+            self.lights_off_at = None;
+            self.set_lights(api, Lights::default()).await?;
+        }
+
+        let Some(deadline) = self.deadline else {
+            return Ok(());
+        };
+        if Instant::now() < deadline {
+            return Ok(());
+        }
+
+        match self.state {
+            AlarmState::ArmingExit => {
+                let state = match self.mode {
+                    ArmMode::Away => AlarmState::ArmedAway,
+                    ArmMode::Stay => AlarmState::ArmedStay,
+                };
+                self.transition(api, state, None).await;
+            }
+            AlarmState::EntryDelay => {
+                self.transition(api, AlarmState::Triggered, None).await;
+            }
+            _ => self.deadline = None,
+        }
+
+        Ok(())
+    }
+
+    fn snapshot(&self) -> NodeSnapshot {
+        NodeSnapshot::Alarm(AlarmSnapshot {
+            state: self.state,
+            mode: self.mode,
+            bypassed_zones: self.bypassed_zones,
+            triggered_zones: self.triggered_zones,
+            triggered_count: self.triggered_count,
+            lights: self.lights,
+            lights_off_in_secs: self
+                .lights_off_at
+                .map(|at| at.saturating_duration_since(Instant::now()).as_secs()),
+        })
+    }
+
+    fn take_events(&mut self) -> Vec<DeviceEventKind> {
+        std::mem::take(&mut self.events)
+    }
 }
 
 pub enum AlarmAction {
-    SetActive(bool),         // set alarm on/off
-    PowerLights(bool, bool), // set front and rear lights on/off
+    SetActive(bool),                 // set alarm on/off (armed away)
+    PowerLights(bool, bool),         // set front and rear lights on/off
+    PowerLightsFor(bool, bool, u32), // same, then off again after the given seconds
+    Arm(ArmMode),
+    Disarm,
+    Bypass(u8),                         // zones to bypass until the next disarm
+    WithCode(String, Box<AlarmAction>), // run the action, authorized by a user code
 }
 
-impl DeviceActionTrait for AlarmAction {}
+impl DeviceActionTrait for AlarmAction {
+    fn specs() -> Vec<ActionSpec> {
+        let code = ArgKind::String;
+        let mode = ArgKind::Enum {
+            values: &["away", "stay"],
+        };
+        vec![
+            ActionSpec::new("set_active", "Arm (away) or disarm the alarm")
+                .arg("active", ArgKind::Bool)
+                .optional("code", code.clone()),
+            ActionSpec::new("power_lights", "Switch the lights on or off")
+                .arg("front", ArgKind::Bool)
+                .arg("rear", ArgKind::Bool),
+            ActionSpec::new("power_lights_for", "Switch the lights, then off again")
+                .arg("front", ArgKind::Bool)
+                .arg("rear", ArgKind::Bool)
+                .arg(
+                    "secs",
+                    ArgKind::Integer {
+                        min: 0,
+                        max: u32::MAX as i64,
+                    },
+                ),
+            ActionSpec::new("arm", "Arm the alarm")
+                .arg("mode", mode)
+                .optional("code", code.clone()),
+            ActionSpec::new("disarm", "Disarm the alarm").optional("code", code.clone()),
+            ActionSpec::new("bypass", "Bypass zones until the next disarm")
+                .arg("zones", ArgKind::Integer { min: 0, max: 255 })
+                .optional("code", code),
+        ]
+    }
+
+    fn parse(name: &str, args: ActionArgs) -> Result<AlarmAction, DeviceError> {
+        let action = match name {
+            "set_active" => AlarmAction::SetActive(args.get("active")?),
+            "power_lights" => AlarmAction::PowerLights(args.get("front")?, args.get("rear")?),
+            "power_lights_for" => AlarmAction::PowerLightsFor(
+                args.get("front")?,
+                args.get("rear")?,
+                args.get("secs")?,
+            ),
+            "arm" => AlarmAction::Arm(args.get("mode")?),
+            "disarm" => AlarmAction::Disarm,
+            "bypass" => AlarmAction::Bypass(args.get("zones")?),
+            _ => return Err(DeviceError::Unsupported),
+        };
+
+        Ok(match args.get_opt("code")? {
+            Some(code) => AlarmAction::WithCode(code, Box::new(action)),
+            None => action,
+        })
+    }
+
+    fn describe(&self) -> (&'static str, Value) {
+        match self {
+            AlarmAction::SetActive(active) => ("set_active", json!({ "active": active })),
+            AlarmAction::PowerLights(front, rear) => {
+                ("power_lights", json!({ "front": front, "rear": rear }))
+            }
+            AlarmAction::PowerLightsFor(front, rear, secs) => (
+                "power_lights_for",
+                json!({ "front": front, "rear": rear, "secs": secs }),
+            ),
+            AlarmAction::Arm(mode) => ("arm", json!({ "mode": mode })),
+            AlarmAction::Disarm => ("disarm", Value::Null),
+            AlarmAction::Bypass(zones) => ("bypass", json!({ "zones": zones })),
+            // only whether a code was given, not the code
+            AlarmAction::WithCode(_, action) => {
+                let (name, mut args) = action.describe();
+                match &mut args {
+                    Value::Object(args) => {
+                        args.insert("code".to_string(), Value::Bool(true));
+                    }
+                    _ => args = json!({ "code": true }),
+                }
+                (name, args)
+            }
+        }
+    }
+}
+
+impl DevicePlugin for AlarmNode {
+    const TYPE_CODE: u8 = 0x01;
+    const TYPE_NAME: &'static str = "alarm";
+
+    type Settings = AlarmConfig;
+
+    fn create(settings: AlarmConfig) -> AlarmNode {
+        AlarmNode::new(settings)
+    }
+}
 
 #[async_trait]
 impl DeviceControllableTrait for AlarmNode {
@@ -34,18 +574,444 @@ impl DeviceControllableTrait for AlarmNode {
 
     async fn handle_action(
         &mut self,
-        api: &dyn ControllerAPI,
+        api: &mut dyn ControllerAPI,
         action: &AlarmAction,
     ) -> Result<(), DeviceError> {
-        match action {
-            AlarmAction::SetActive(active) => {
-                self.active = *active;
-            }
-            AlarmAction::PowerLights(front, rear) => {
-                let z = api.query_frame(1, Some(0)).await;
-            }
+        let (code, action) = match action {
+            AlarmAction::WithCode(code, action) => (Some(code.as_str()), action.as_ref()),
+            action => (None, action),
         };
 
-        Ok(())
+        // a code given for an unprotected action is ignored, not to count
+        // toward the lockout
+        if self.config.codes_required() && self.is_protected(action) {
+            let code = code.ok_or(DeviceError::CodeRequired)?;
+            let disarm = matches!(action, AlarmAction::SetActive(false) | AlarmAction::Disarm);
+            if self.check_code(code)? == CodeKind::Duress && disarm {
+                // silent: the node is disarmed as usual, only the event tells
+                self.events.push(DeviceEventKind::AlarmDuress);
+            }
+        }
+
+        self.run_action(api, action).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bus acknowledging every request, recording the frames sent
+    #[derive(Default)]
+    struct TestBus {
+        sent: Vec<[u8; 8]>,
+    }
+
+    #[async_trait]
+    impl ControllerAPI for TestBus {
+        async fn command(&mut self, data: [u8; 8]) {
+            self.sent.push(data);
+        }
+
+        async fn request(
+            &mut self,
+            data: [u8; 8],
+            _is_ack: &(dyn for<'f> Fn(&'f CanFrame) -> bool + Sync),
+            _timeout: Duration,
+        ) -> Result<CanFrame, DeviceError> {
+            self.sent.push(data);
+            let mut ack = data;
+            ack[0] |= ACK;
+            Ok(CanFrame { id: 1, data: ack })
+        }
+
+        async fn query_frame(&mut self, _id: u32, _timeout_ms: Option<u32>) -> u32 {
+            0
+        }
+    }
+
+    fn alarm() -> AlarmNode {
+        AlarmNode::new(AlarmConfig {
+            exit_delay: 0,
+            entry_delay: 0,
+            entry_zones: 0x01,
+            interior_zones: 0x04,
+            ..Default::default()
+        })
+    }
+
+    fn trigger_frame(zones: u8) -> CanFrame {
+        CanFrame {
+            id: 1,
+            data: [FRAME_TRIGGER, zones, 0, 0, 0, 0, 0, 0],
+        }
+    }
+
+    async fn armed(mode: ArmMode) -> (AlarmNode, TestBus) {
+        let (mut alarm, mut bus) = (alarm(), TestBus::default());
+        alarm
+            .handle_action(&mut bus, &AlarmAction::Arm(mode))
+            .await
+            .unwrap();
+        assert_eq!(alarm.state, AlarmState::ArmingExit);
+        alarm.tick(&mut bus).await.unwrap();
+        alarm.take_events();
+        bus.sent.clear();
+        (alarm, bus)
+    }
+
+    #[tokio::test]
+    async fn arming_ends_with_the_exit_delay() {
+        let (mut alarm, mut bus) = (alarm(), TestBus::default());
+        alarm
+            .handle_action(&mut bus, &AlarmAction::Arm(ArmMode::Stay))
+            .await
+            .unwrap();
+        alarm.tick(&mut bus).await.unwrap();
+
+        assert_eq!(alarm.state, AlarmState::ArmedStay);
+        assert_eq!(
+            bus.sent,
+            [
+                [CMD_SET_STATE, 1, 0, 0, 0, 0, 0, 0],
+                [CMD_SET_STATE, 3, 0, 0, 0, 0, 0, 0]
+            ]
+        );
+        assert_eq!(
+            alarm.take_events(),
+            [
+                DeviceEventKind::AlarmStateChanged {
+                    state: AlarmState::ArmingExit
+                },
+                DeviceEventKind::AlarmStateChanged {
+                    state: AlarmState::ArmedStay
+                },
+            ]
+        );
+
+        let ret = alarm
+            .handle_action(&mut bus, &AlarmAction::Arm(ArmMode::Away))
+            .await;
+        assert!(matches!(ret, Err(DeviceError::InvalidState)));
+    }
+
+    #[tokio::test]
+    async fn entry_zone_starts_the_entry_delay() {
+        let (mut alarm, mut bus) = armed(ArmMode::Away).await;
+
+        alarm
+            .handle_frame(&mut bus, &trigger_frame(0x01))
+            .await
+            .unwrap();
+        assert_eq!(alarm.state, AlarmState::EntryDelay);
+
+        // disarmed in time
+        alarm
+            .handle_action(&mut bus, &AlarmAction::Disarm)
+            .await
+            .unwrap();
+        assert_eq!(alarm.state, AlarmState::Disarmed);
+        alarm.tick(&mut bus).await.unwrap();
+        assert_eq!(alarm.state, AlarmState::Disarmed);
+    }
+
+    #[tokio::test]
+    async fn entry_delay_expiry_triggers() {
+        let (mut alarm, mut bus) = armed(ArmMode::Away).await;
+
+        alarm
+            .handle_frame(&mut bus, &trigger_frame(0x01))
+            .await
+            .unwrap();
+        alarm.tick(&mut bus).await.unwrap();
+
+        assert_eq!(alarm.state, AlarmState::Triggered);
+        assert_eq!(alarm.triggered_zones, 0x01);
+        assert_eq!(alarm.lights, Lights::new(true, true));
+        assert!(bus
+            .sent
+            .contains(&[CMD_LIGHTS, LIGHT_FRONT | LIGHT_REAR, 0, 0, 0, 0, 0, 0]));
+    }
+
+    #[tokio::test]
+    async fn other_zones_trigger_at_once() {
+        let (mut alarm, mut bus) = armed(ArmMode::Away).await;
+
+        alarm
+            .handle_frame(&mut bus, &trigger_frame(0x02))
+            .await
+            .unwrap();
+
+        assert_eq!(alarm.state, AlarmState::Triggered);
+        assert_eq!(alarm.triggered_count, 1);
+        assert_eq!(
+            alarm.take_events()[..2],
+            [
+                DeviceEventKind::AlarmTriggered { zones: 0x02 },
+                DeviceEventKind::AlarmStateChanged {
+                    state: AlarmState::Triggered
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stay_mode_ignores_interior_zones() {
+        let (mut alarm, mut bus) = armed(ArmMode::Stay).await;
+
+        alarm
+            .handle_frame(&mut bus, &trigger_frame(0x04))
+            .await
+            .unwrap();
+        assert_eq!(alarm.state, AlarmState::ArmedStay);
+        assert!(alarm.take_events().is_empty());
+
+        alarm
+            .handle_frame(&mut bus, &trigger_frame(0x06))
+            .await
+            .unwrap();
+        assert_eq!(alarm.state, AlarmState::Triggered);
+        assert_eq!(alarm.triggered_zones, 0x02);
+    }
+
+    #[tokio::test]
+    async fn bypassed_zones_are_ignored_until_disarmed() {
+        let (mut alarm, mut bus) = (alarm(), TestBus::default());
+        alarm
+            .handle_action(&mut bus, &AlarmAction::Bypass(0x02))
+            .await
+            .unwrap();
+        alarm
+            .handle_action(&mut bus, &AlarmAction::Arm(ArmMode::Away))
+            .await
+            .unwrap();
+        alarm.tick(&mut bus).await.unwrap();
+
+        let ret = alarm
+            .handle_action(&mut bus, &AlarmAction::Bypass(0x04))
+            .await;
+        assert!(matches!(ret, Err(DeviceError::InvalidState)));
+
+        alarm
+            .handle_frame(&mut bus, &trigger_frame(0x02))
+            .await
+            .unwrap();
+        assert_eq!(alarm.state, AlarmState::ArmedAway);
+
+        alarm
+            .handle_action(&mut bus, &AlarmAction::Disarm)
+            .await
+            .unwrap();
+        assert_eq!(alarm.bypassed_zones, 0);
+    }
+
+    #[tokio::test]
+    async fn only_trigger_frames_trigger() {
+        let (mut alarm, mut bus) = armed(ArmMode::Away).await;
+
+        // acknowledgements and other opcodes with the trigger bit set
+        for opcode in [FRAME_TRIGGER | ACK, 0x03, CMD_LIGHTS | ACK | FRAME_TRIGGER] {
+            let frame = CanFrame {
+                id: 1,
+                data: [opcode, 0x02, 0, 0, 0, 0, 0, 0],
+            };
+            alarm.handle_frame(&mut bus, &frame).await.unwrap();
+        }
+        assert_eq!(alarm.state, AlarmState::ArmedAway);
+
+        // disarmed, nothing happens
+        alarm
+            .handle_action(&mut bus, &AlarmAction::Disarm)
+            .await
+            .unwrap();
+        alarm
+            .handle_frame(&mut bus, &trigger_frame(0x02))
+            .await
+            .unwrap();
+        assert_eq!(alarm.state, AlarmState::Disarmed);
+        assert_eq!(alarm.triggered_count, 0);
+    }
+
+    fn with_codes(lockout: u32, quick_arm: bool) -> AlarmNode {
+        AlarmNode::new(AlarmConfig {
+            exit_delay: 0,
+            user_codes: vec![crate::secret::hash_secret("s4lt", "1234")],
+            duress_codes: vec![crate::secret::hash_secret("s4lt", "9999")],
+            quick_arm,
+            max_attempts: 3,
+            lockout,
+            ..Default::default()
+        })
+    }
+
+    fn with_code(code: &str, action: AlarmAction) -> AlarmAction {
+        AlarmAction::WithCode(code.to_string(), Box::new(action))
+    }
+
+    #[tokio::test]
+    async fn codes_are_required_once_configured() {
+        let (mut alarm, mut bus) = (with_codes(300, false), TestBus::default());
+
+        let ret = alarm
+            .handle_action(&mut bus, &AlarmAction::Arm(ArmMode::Away))
+            .await;
+        assert!(matches!(ret, Err(DeviceError::CodeRequired)));
+        alarm
+            .handle_action(
+                &mut bus,
+                &with_code("1234", AlarmAction::Arm(ArmMode::Away)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(alarm.state, AlarmState::ArmingExit);
+
+        let ret = alarm.handle_action(&mut bus, &AlarmAction::Disarm).await;
+        assert!(matches!(ret, Err(DeviceError::CodeRequired)));
+        // the lights are not protected
+        alarm
+            .handle_action(&mut bus, &AlarmAction::PowerLights(true, false))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn quick_arm_only_skips_the_code_to_arm() {
+        let (mut alarm, mut bus) = (with_codes(300, true), TestBus::default());
+
+        alarm
+            .handle_action(&mut bus, &AlarmAction::Arm(ArmMode::Away))
+            .await
+            .unwrap();
+        let ret = alarm.handle_action(&mut bus, &AlarmAction::Disarm).await;
+        assert!(matches!(ret, Err(DeviceError::CodeRequired)));
+    }
+
+    #[tokio::test]
+    async fn failed_attempts_lock_the_codes_out() {
+        let (mut alarm, mut bus) = (with_codes(300, false), TestBus::default());
+        let disarm = |code| with_code(code, AlarmAction::Disarm);
+
+        for attempts in 1..=3 {
+            let ret = alarm.handle_action(&mut bus, &disarm("0000")).await;
+            assert!(matches!(ret, Err(DeviceError::InvalidCode)));
+            assert_eq!(
+                alarm.take_events()[0],
+                DeviceEventKind::AlarmCodeRejected { attempts }
+            );
+        }
+        assert!(alarm.locked_until.is_some());
+
+        // even the right code is refused until the lockout ends
+        let ret = alarm.handle_action(&mut bus, &disarm("1234")).await;
+        assert!(matches!(ret, Err(DeviceError::LockedOut)));
+        let ret = alarm.handle_action(&mut bus, &disarm("9999")).await;
+        assert!(matches!(ret, Err(DeviceError::LockedOut)));
+    }
+
+    #[tokio::test]
+    async fn lockout_ends_and_success_resets_the_attempts() {
+        let (mut alarm, mut bus) = (with_codes(0, false), TestBus::default());
+        let disarm = |code| with_code(code, AlarmAction::Disarm);
+
+        for _ in 0..3 {
+            let _ = alarm.handle_action(&mut bus, &disarm("0000")).await;
+        }
+        assert!(alarm
+            .take_events()
+            .contains(&DeviceEventKind::AlarmLockedOut));
+
+        // expired at once
+        alarm
+            .handle_action(&mut bus, &disarm("1234"))
+            .await
+            .unwrap();
+        assert_eq!(alarm.locked_until, None);
+
+        let _ = alarm.handle_action(&mut bus, &disarm("0000")).await;
+        alarm
+            .handle_action(&mut bus, &disarm("1234"))
+            .await
+            .unwrap();
+        assert_eq!(alarm.failed_attempts, 0);
+    }
+
+    #[tokio::test]
+    async fn codes_are_only_checked_when_needed() {
+        let (mut alarm, mut bus) = (with_codes(300, false), TestBus::default());
+
+        // the lights need no code, a wrong one does not count
+        for _ in 0..3 {
+            alarm
+                .handle_action(
+                    &mut bus,
+                    &with_code("0000", AlarmAction::PowerLights(true, true)),
+                )
+                .await
+                .unwrap();
+        }
+        assert!(alarm.take_events().iter().all(|event| !matches!(
+            event,
+            DeviceEventKind::AlarmCodeRejected { .. } | DeviceEventKind::AlarmLockedOut
+        )));
+        assert_eq!(alarm.locked_until, None);
+    }
+
+    #[tokio::test]
+    async fn codes_are_ignored_when_none_is_configured() {
+        let (mut alarm, mut bus) = (alarm(), TestBus::default());
+        alarm
+            .handle_action(
+                &mut bus,
+                &with_code("0000", AlarmAction::Arm(ArmMode::Away)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(alarm.state, AlarmState::ArmingExit);
+        assert_eq!(alarm.failed_attempts, 0);
+    }
+
+    #[tokio::test]
+    async fn duress_code_only_raises_on_disarm() {
+        let (mut alarm, mut bus) = (with_codes(300, false), TestBus::default());
+
+        alarm
+            .handle_action(
+                &mut bus,
+                &with_code("9999", AlarmAction::Arm(ArmMode::Away)),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(alarm.state, AlarmState::ArmingExit);
+        assert!(!alarm.take_events().contains(&DeviceEventKind::AlarmDuress));
+    }
+
+    #[tokio::test]
+    async fn duress_code_disarms_silently() {
+        let (mut alarm, mut bus) = (with_codes(300, false), TestBus::default());
+        alarm
+            .handle_action(
+                &mut bus,
+                &with_code("1234", AlarmAction::Arm(ArmMode::Away)),
+            )
+            .await
+            .unwrap();
+        alarm.take_events();
+
+        alarm
+            .handle_action(&mut bus, &with_code("9999", AlarmAction::Disarm))
+            .await
+            .unwrap();
+
+        assert_eq!(alarm.state, AlarmState::Disarmed);
+        assert_eq!(
+            alarm.take_events(),
+            [
+                DeviceEventKind::AlarmDuress,
+                DeviceEventKind::AlarmStateChanged {
+                    state: AlarmState::Disarmed
+                },
+            ]
+        );
     }
 }
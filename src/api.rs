@@ -0,0 +1,11 @@
+//! Bodies of the REST API, shared by the web server and its clients such as
+//! the `ctl` binary. The device snapshots, events and history pages are
+//! served as is.
+use serde::{Deserialize, Serialize};
+
+/// `GET /query`
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct QueryResponse {
+    pub id: u32,
+}
@@ -0,0 +1,315 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{auth::Identity, storage::StorageError};
+
+/// Who asked for an action
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Origin {
+    Http { user: String }, // authenticated user, or "anonymous"
+    Mqtt,
+    ControlSocket { uid: u32 }, // peer of the local socket, see `rpc`
+    Rule { name: String },      // automation rule, see `rules`
+    Schedule,                   // weekly program of the device itself
+    Internal,                   // the application, through a `DeviceHandle`
+}
+
+impl Origin {
+    /// Name of the origin type, as used in the serialized form and in queries
+    pub fn name(&self) -> &'static str {
+        match self {
+            Origin::Http { .. } => "http",
+            Origin::Mqtt => "mqtt",
+            Origin::ControlSocket { .. } => "control_socket",
+            Origin::Rule { .. } => "rule",
+            Origin::Schedule => "schedule",
+            Origin::Internal => "internal",
+        }
+    }
+}
+
+impl From<&Identity> for Origin {
+    fn from(identity: &Identity) -> Origin {
+        Origin::Http {
+            user: identity.name.clone(),
+        }
+    }
+}
+
+/// Action run on a device, or denied, with its outcome
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub origin: Origin,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<u32>, // none for the denied requests of other routes
+    pub action: String,
+    pub args: Value, // null if none, or if they could not be parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>, // none on success
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub path: Option<PathBuf>, // None keeps the log in memory only
+    pub max_size: u64,         // in bytes, before the file is rotated
+    pub keep: usize,           // rotated files kept, as <path>.1 (newest) ...
+}
+
+impl Default for AuditConfig {
+    fn default() -> AuditConfig {
+        AuditConfig {
+            path: Some(PathBuf::from("audit.jsonl")),
+            max_size: 1024 * 1024,
+            keep: 4,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AuditQuery {
+    pub device_id: Option<u32>,
+    pub origin: Option<String>, // origin type, e.g. "http"
+    pub user: Option<String>,   // HTTP user
+    pub action: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct AuditPage {
+    pub total: usize,
+    pub offset: usize,
+    pub records: Vec<AuditRecord>,
+}
+
+/// Append-only log of the device actions, mirrored to a JSON lines file.
+///
+/// The file is renamed to `<path>.1` once larger than `max_size`, shifting the
+/// previous ones up to `<path>.<keep>`, the oldest being removed. The records
+/// of the current and kept files stay in memory, to be queried.
+#[derive(Debug)]
+pub struct AuditStore {
+    config: AuditConfig,
+    records: VecDeque<AuditRecord>,
+    counts: VecDeque<usize>, // records per file, oldest first, the current last
+    size: u64,               // of the current file
+    file: Option<File>,
+}
+
+impl AuditStore {
+    pub fn open(config: AuditConfig) -> Result<AuditStore, StorageError> {
+        // without the storage feature, the log is only kept in memory
+        #[cfg(not(feature = "storage"))]
+        let config = AuditConfig {
+            path: None,
+            ..config
+        };
+
+        let mut records = VecDeque::new();
+        let mut counts = VecDeque::new();
+        let mut size = 0;
+
+        if let Some(path) = &config.path {
+            let paths = (1..=config.keep)
+                .rev()
+                .map(|n| rotated_path(path, n))
+                .chain([path.clone()]);
+            for path in paths {
+                let before = records.len();
+                size = 0;
+                if path.exists() {
+                    size = fs::metadata(&path)?.len();
+                    let reader = BufReader::new(File::open(&path)?);
+                    for line in reader.lines() {
+                        // skip truncated or corrupted lines rather than failing
+                        if let Ok(record) = serde_json::from_str(&line?) {
+                            records.push_back(record);
+                        }
+                    }
+                }
+                counts.push_back(records.len() - before);
+            }
+        } else {
+            counts.push_back(0);
+        }
+
+        let file = match &config.path {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+
+        Ok(AuditStore {
+            config,
+            records,
+            counts,
+            size,
+            file,
+        })
+    }
+
+    pub fn append(&mut self, record: AuditRecord) -> Result<(), StorageError> {
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_size {
+            self.rotate()?;
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(&line)?;
+        }
+        self.size += line.len() as u64;
+
+        self.records.push_back(record);
+        if let Some(count) = self.counts.back_mut() {
+            *count += 1;
+        }
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), StorageError> {
+        if let Some(path) = &self.config.path {
+            if self.config.keep == 0 {
+                fs::remove_file(path)?;
+            } else {
+                for n in (1..self.config.keep).rev() {
+                    let from = rotated_path(path, n);
+                    if from.exists() {
+                        fs::rename(&from, rotated_path(path, n + 1))?;
+                    }
+                }
+                fs::rename(path, rotated_path(path, 1))?;
+            }
+            self.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        }
+        self.size = 0;
+
+        self.counts.push_back(0);
+        while self.counts.len() > self.config.keep + 1 {
+            let dropped = self.counts.pop_front().unwrap_or(0);
+            self.records.drain(..dropped);
+        }
+
+        Ok(())
+    }
+
+    pub fn query(&self, query: &AuditQuery) -> AuditPage {
+        let matching: Vec<&AuditRecord> = self
+            .records
+            .iter()
+            .filter(|r| query.device_id.is_none_or(|id| r.device_id == Some(id)))
+            .filter(|r| query.origin.as_deref().is_none_or(|o| r.origin.name() == o))
+            .filter(|r| {
+                query
+                    .user
+                    .as_deref()
+                    .is_none_or(|u| matches!(&r.origin, Origin::Http { user } if user == u))
+            })
+            .filter(|r| query.action.as_deref().is_none_or(|a| r.action == a))
+            .filter(|r| query.from.is_none_or(|from| r.timestamp >= from))
+            .filter(|r| query.to.is_none_or(|to| r.timestamp < to))
+            .collect();
+
+        AuditPage {
+            total: matching.len(),
+            offset: query.offset,
+            records: matching
+                .into_iter()
+                .skip(query.offset)
+                .take(query.limit)
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// `<path>.<n>`, e.g. audit.jsonl.1
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+#[cfg(all(test, feature = "storage"))]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn record(n: usize) -> AuditRecord {
+        AuditRecord {
+            timestamp: DateTime::from_timestamp(0, 0).unwrap(),
+            origin: Origin::Mqtt,
+            device_id: Some(1),
+            action: format!("action{}", n),
+            args: Value::Null,
+            error: None,
+        }
+    }
+
+    fn actions(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<AuditRecord>(line).unwrap().action)
+            .collect()
+    }
+
+    fn all_actions(store: &AuditStore) -> Vec<String> {
+        let page = store.query(&AuditQuery {
+            limit: usize::MAX,
+            ..Default::default()
+        });
+        page.records.into_iter().map(|r| r.action).collect()
+    }
+
+    #[test]
+    fn files_are_rotated_and_the_oldest_removed() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let line = serde_json::to_vec(&record(1)).unwrap().len() as u64 + 1;
+        let config = AuditConfig {
+            path: Some(path.clone()),
+            max_size: 2 * line, // two records per file
+            keep: 2,
+        };
+
+        let mut store = AuditStore::open(config.clone()).unwrap();
+        for n in 1..=7 {
+            store.append(record(n)).unwrap();
+        }
+
+        assert_eq!(actions(&path), ["action7"]);
+        assert_eq!(actions(&rotated_path(&path, 1)), ["action5", "action6"]);
+        assert_eq!(actions(&rotated_path(&path, 2)), ["action3", "action4"]);
+        assert!(!rotated_path(&path, 3).exists());
+        let expected: Vec<_> = (3..=7).map(|n| format!("action{}", n)).collect();
+        assert_eq!(all_actions(&store), expected);
+        drop(store);
+
+        // the kept files are loaded again, oldest first
+        let mut store = AuditStore::open(config).unwrap();
+        assert_eq!(all_actions(&store), expected);
+        store.append(record(8)).unwrap();
+        store.append(record(9)).unwrap();
+        assert_eq!(actions(&path), ["action9"]);
+        assert_eq!(actions(&rotated_path(&path, 1)), ["action7", "action8"]);
+        let expected: Vec<_> = (5..=9).map(|n| format!("action{}", n)).collect();
+        assert_eq!(all_actions(&store), expected);
+    }
+}
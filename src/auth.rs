@@ -0,0 +1,331 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{device::NodeSnapshot, secret::verify_secret};
+
+/// Access level of an API client, each role having the rights of the lower ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Viewer,   // stats, devices and their history
+    Operator, // heater actions, schedules, node queries
+    Admin,    // alarm arming and disarming, node resets
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum AuthError {
+    #[error("Missing credentials")]
+    Missing,
+    #[error("Invalid credentials")]
+    Invalid,
+    #[error("Insufficient role")]
+    Forbidden,
+    #[error("Cross-site request refused")]
+    CrossSite,
+}
+
+/// User of the basic authentication
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserConfig {
+    pub name: String,
+    pub password: String, // hashed, see `secret::hash_secret`
+    pub role: Role,
+}
+
+/// API token, sent as `Authorization: Bearer <token>`
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenConfig {
+    pub name: String,  // identifies the client in the audit log
+    pub token: String, // hashed, see `secret::hash_secret`
+    pub role: Role,
+}
+
+/// TLS client certificate, verified by the configured CA
+#[derive(Debug, Clone, Deserialize)]
+pub struct CertificateConfig {
+    pub name: String, // common name of the certificate subject
+    pub role: Role,
+}
+
+/// Clients of the web API. Without any user, token or certificate, every
+/// client has the `anonymous` role, viewer unless set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub users: Vec<UserConfig>,
+    pub tokens: Vec<TokenConfig>,
+    pub certificates: Vec<CertificateConfig>,
+    pub anonymous: Option<Role>, // role of the clients without credentials
+}
+
+/// Authenticated client
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub name: String,
+    pub role: Role,
+}
+
+impl Identity {
+    fn anonymous(role: Role) -> Identity {
+        Identity {
+            name: "anonymous".to_string(),
+            role,
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.users.is_empty() || !self.tokens.is_empty() || !self.certificates.is_empty()
+    }
+
+    /// Client of a request, from the common name of its verified TLS
+    /// certificate if known, else from its `Authorization` header
+    pub fn authenticate(
+        &self,
+        common_name: Option<&str>,
+        authorization: Option<&str>,
+    ) -> Result<Identity, AuthError> {
+        if !self.is_enabled() {
+            // an open API has to be asked for explicitly
+            return Ok(Identity::anonymous(self.anonymous.unwrap_or(Role::Viewer)));
+        }
+
+        let certificate = common_name.and_then(|common_name| {
+            self.certificates
+                .iter()
+                .find(|certificate| certificate.name == common_name)
+        });
+        if let Some(certificate) = certificate {
+            return Ok(Identity {
+                name: certificate.name.clone(),
+                role: certificate.role,
+            });
+        }
+
+        let Some(authorization) = authorization else {
+            return self
+                .anonymous
+                .map(Identity::anonymous)
+                .ok_or(AuthError::Missing);
+        };
+
+        let (scheme, credentials) = authorization.split_once(' ').ok_or(AuthError::Invalid)?;
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("bearer") {
+            self.tokens
+                .iter()
+                .find(|token| verify_secret(&token.token, credentials))
+                .map(|token| Identity {
+                    name: token.name.clone(),
+                    role: token.role,
+                })
+                .ok_or(AuthError::Invalid)
+        } else if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = BASE64.decode(credentials).map_err(|_| AuthError::Invalid)?;
+            let decoded = String::from_utf8(decoded).map_err(|_| AuthError::Invalid)?;
+            let (name, password) = decoded.split_once(':').ok_or(AuthError::Invalid)?;
+            self.users
+                .iter()
+                .find(|user| user.name == name && verify_secret(&user.password, password))
+                .map(|user| Identity {
+                    name: user.name.clone(),
+                    role: user.role,
+                })
+                .ok_or(AuthError::Invalid)
+        } else {
+            Err(AuthError::Invalid)
+        }
+    }
+}
+
+/// Role needed by a named action: the node reset and the alarm security
+/// actions (anything but its lights) are restricted to the admins
+pub fn named_action_role(node: &NodeSnapshot, name: &str) -> Role {
+    match (node, name) {
+        (_, "reset") => Role::Admin,
+        (_, "identify" | "ping" | "read_version") => Role::Operator,
+        (NodeSnapshot::Alarm(_), "power_lights" | "power_lights_for") => Role::Operator,
+        (NodeSnapshot::Alarm(_), _) => Role::Admin,
+        _ => Role::Operator,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{alarm::AlarmNode, device::DeviceTrait, heater::HeaterNode, secret::hash_secret};
+
+    fn config() -> AuthConfig {
+        AuthConfig {
+            users: vec![
+                UserConfig {
+                    name: "admin".to_string(),
+                    password: hash_secret("s4lt", "password"),
+                    role: Role::Admin,
+                },
+                UserConfig {
+                    name: "guest".to_string(),
+                    password: hash_secret("s4lt", "guest"),
+                    role: Role::Viewer,
+                },
+            ],
+            tokens: vec![TokenConfig {
+                name: "ci".to_string(),
+                token: hash_secret("s4lt", "t0ken"),
+                role: Role::Operator,
+            }],
+            certificates: vec![CertificateConfig {
+                name: "homeassistant".to_string(),
+                role: Role::Operator,
+            }],
+            anonymous: None,
+        }
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", BASE64.encode(credentials))
+    }
+
+    fn identity(name: &str, role: Role) -> Result<Identity, AuthError> {
+        Ok(Identity {
+            name: name.to_string(),
+            role,
+        })
+    }
+
+    #[test]
+    fn basic_users_need_their_password() {
+        let auth = config();
+
+        for (authorization, expected) in [
+            (basic("admin:password"), identity("admin", Role::Admin)),
+            (basic("guest:guest"), identity("guest", Role::Viewer)),
+            (basic("admin:guest"), Err(AuthError::Invalid)),
+            (basic("nobody:password"), Err(AuthError::Invalid)),
+            (basic("admin"), Err(AuthError::Invalid)),
+            ("Basic !!!".to_string(), Err(AuthError::Invalid)),
+        ] {
+            assert_eq!(
+                auth.authenticate(None, Some(&authorization)),
+                expected,
+                "{}",
+                authorization
+            );
+        }
+        // the scheme is case insensitive
+        let authorization = basic("admin:password").replace("Basic", "basic");
+        assert_eq!(
+            auth.authenticate(None, Some(&authorization)),
+            identity("admin", Role::Admin)
+        );
+    }
+
+    #[test]
+    fn bearer_tokens_are_matched() {
+        let auth = config();
+
+        assert_eq!(
+            auth.authenticate(None, Some("Bearer t0ken")),
+            identity("ci", Role::Operator)
+        );
+        for authorization in ["Bearer t0ke", "Bearer", "Digest t0ken", "t0ken"] {
+            assert_eq!(
+                auth.authenticate(None, Some(authorization)),
+                Err(AuthError::Invalid),
+                "{}",
+                authorization
+            );
+        }
+    }
+
+    #[test]
+    fn known_certificates_come_first() {
+        let auth = config();
+
+        assert_eq!(
+            auth.authenticate(Some("homeassistant"), Some("Bearer wrong")),
+            identity("homeassistant", Role::Operator)
+        );
+        // unknown names fall back to the header
+        assert_eq!(
+            auth.authenticate(Some("other"), Some(&basic("admin:password"))),
+            identity("admin", Role::Admin)
+        );
+        assert_eq!(
+            auth.authenticate(Some("other"), None),
+            Err(AuthError::Missing)
+        );
+    }
+
+    #[test]
+    fn clients_without_credentials_are_anonymous_if_allowed() {
+        let auth = AuthConfig {
+            anonymous: Some(Role::Viewer),
+            ..config()
+        };
+
+        assert_eq!(
+            auth.authenticate(None, None),
+            identity("anonymous", Role::Viewer)
+        );
+        // wrong credentials are not downgraded
+        assert_eq!(
+            auth.authenticate(None, Some(&basic("admin:guest"))),
+            Err(AuthError::Invalid)
+        );
+    }
+
+    #[test]
+    fn open_api_clients_are_viewers_by_default() {
+        let auth = AuthConfig::default();
+        assert!(!auth.is_enabled());
+
+        assert_eq!(
+            auth.authenticate(None, None),
+            identity("anonymous", Role::Viewer)
+        );
+        assert_eq!(
+            auth.authenticate(None, Some(&basic("admin:password"))),
+            identity("anonymous", Role::Viewer)
+        );
+
+        let auth = AuthConfig {
+            anonymous: Some(Role::Admin),
+            ..Default::default()
+        };
+        assert_eq!(
+            auth.authenticate(None, None),
+            identity("anonymous", Role::Admin)
+        );
+    }
+
+    #[test]
+    fn action_roles() {
+        let alarm = AlarmNode::default().snapshot();
+        let heater = HeaterNode::default().snapshot();
+        let other = NodeSnapshot::Other(serde_json::json!({ "type": "meter" }));
+
+        for (node, name, role) in [
+            (&alarm, "power_lights", Role::Operator),
+            (&alarm, "power_lights_for", Role::Operator),
+            (&alarm, "arm", Role::Admin),
+            (&alarm, "disarm", Role::Admin),
+            (&alarm, "bypass", Role::Admin),
+            (&alarm, "set_active", Role::Admin),
+            (&heater, "heater_power", Role::Operator),
+            (&heater, "set_schedule", Role::Operator),
+            (&other, "anything", Role::Operator),
+        ] {
+            assert_eq!(named_action_role(node, name), role, "{}", name);
+        }
+        // the management actions, whatever the node
+        for node in [&alarm, &heater, &other] {
+            assert_eq!(named_action_role(node, "reset"), Role::Admin);
+            for name in ["identify", "ping", "read_version"] {
+                assert_eq!(named_action_role(node, name), Role::Operator);
+            }
+        }
+    }
+}
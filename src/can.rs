@@ -1,36 +1,57 @@
+#[cfg(feature = "socketcan")]
+use crate::socketcan::CanSocket;
 use crate::utils::Sock;
 use chrono::{DateTime, Utc};
-use serde::Serialize;
-use std::num::Wrapping;
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, io, num::Wrapping};
 use tokio::time::Duration;
 
-#[derive(Default, Debug, Serialize, Clone)]
+#[derive(Default, Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct CanStats {
     pub rx: u32,
     pub tx: u32,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(default)]
 pub struct CanConfig {
     pub iface: String,
+    pub simulated: bool, // loopback simulation instead of the real bus
 }
 
 impl Default for CanConfig {
     fn default() -> CanConfig {
         CanConfig {
             iface: "vcan0".to_string(),
+            simulated: !cfg!(feature = "socketcan"),
         }
     }
 }
 
 #[derive(Debug)]
 pub struct CanInterface {
+    backend: Backend,
+    pending: VecDeque<CanFrame>,
+    pub stats: CanStats,
+}
+
+#[derive(Debug)]
+enum Backend {
+    Simulated(Simulation),
+    #[cfg(feature = "socketcan")]
+    Socket(CanSocket),
+}
+
+/// Frames sent are received back after DELAY, their first byte shifted
+#[derive(Debug)]
+struct Simulation {
     _sock: Sock,
     _n: Wrapping<u8>,
     buf: Vec<CanFrameLoopback>,
-    pub stats: CanStats,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct CanFrame {
     pub id: u32,
     pub data: [u8; 8],
@@ -45,33 +66,102 @@ struct CanFrameLoopback {
 const DELAY: u64 = 750;
 
 impl CanInterface {
-    pub fn new(_config: CanConfig) -> CanInterface {
-        CanInterface {
-            _sock: Sock::new(),
-            _n: Wrapping(0),
-            buf: Vec::new(),
+    /// Open the configured bus, the real one requires the `socketcan` feature
+    pub fn new(config: CanConfig) -> io::Result<CanInterface> {
+        let backend = if config.simulated {
+            Backend::Simulated(Simulation {
+                _sock: Sock::new(),
+                _n: Wrapping(0),
+                buf: Vec::new(),
+            })
+        } else {
+            Self::open(&config.iface)?
+        };
+
+        Ok(CanInterface {
+            backend,
+            pending: VecDeque::new(),
             stats: CanStats::default(),
-        }
+        })
+    }
+
+    #[cfg(feature = "socketcan")]
+    fn open(iface: &str) -> io::Result<Backend> {
+        Ok(Backend::Socket(CanSocket::open(iface)?))
+    }
+
+    #[cfg(not(feature = "socketcan"))]
+    fn open(_iface: &str) -> io::Result<Backend> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Built without the socketcan feature, only the simulated bus is available",
+        ))
     }
 
     pub async fn send(&mut self, frame: CanFrame) {
         self.stats.tx += 1;
-        self._n += frame.data[0] as u8;
-        self.buf.push(CanFrameLoopback {
-            frame,
-            push_timestamp: Utc::now(),
-        });
+        match &mut self.backend {
+            Backend::Simulated(sim) => {
+                sim._n += frame.data[0];
+                sim.buf.push(CanFrameLoopback {
+                    frame,
+                    push_timestamp: Utc::now(),
+                });
+            }
+            #[cfg(feature = "socketcan")]
+            Backend::Socket(socket) => {
+                if let Err(err) = socket.send(&frame).await {
+                    println!("Failed to send frame {:?}: {}", frame, err);
+                }
+            }
+        }
     }
 
+    /// Put back a received frame, it will be returned by the next `recv()`
+    pub fn requeue(&mut self, frame: CanFrame) {
+        self.pending.push_back(frame);
+    }
+
+    /// Next received frame. On the simulated bus, frames are only looped back
+    /// with `loopback`. On a real bus, `loopback` returns immediately and
+    /// otherwise waits for a frame.
     pub async fn recv(&mut self, loopback: bool) -> Option<CanFrame> {
+        if let Some(frame) = self.pending.pop_front() {
+            return Some(frame);
+        }
+
+        let frame = match &mut self.backend {
+            Backend::Simulated(sim) => sim.recv(loopback),
+            #[cfg(feature = "socketcan")]
+            Backend::Socket(socket) => {
+                let ret = if loopback {
+                    socket.try_recv()
+                } else {
+                    socket.recv().await.map(Some)
+                };
+                ret.unwrap_or_else(|err| {
+                    println!("Failed to receive frame: {}", err);
+                    None
+                })
+            }
+        };
+
+        if frame.is_some() {
+            self.stats.rx += 1;
+        }
+        frame
+    }
+}
+
+impl Simulation {
+    fn recv(&mut self, loopback: bool) -> Option<CanFrame> {
         let now = Utc::now();
 
         if loopback {
-            if let Some(lp_frame) = self.buf.get(0) {
+            if let Some(lp_frame) = self.buf.first() {
                 if lp_frame.push_timestamp + Duration::from_millis(DELAY) < now {
                     let mut frame = self.buf.pop().unwrap().frame;
                     frame.data[0] = frame.data[0].wrapping_add(self._n.0);
-                    self.stats.rx += 1;
                     return Some(frame);
                 }
             }
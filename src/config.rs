@@ -0,0 +1,87 @@
+use serde::Deserialize;
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+#[cfg(feature = "mqtt")]
+use crate::mqtt::MqttConfig;
+use crate::{auth::AuthConfig, can::CanConfig, controller::ControllerConfig, rpc::RpcConfig};
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid config file: {0}")]
+    Parse(#[from] toml::de::Error),
+}
+
+/// HTTPS of the web server, needs the `tls` feature
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    pub cert: PathBuf, // PEM certificate chain
+    pub key: PathBuf,  // PEM private key
+    // PEM CA certificates verifying the client certificates, none disabling them
+    pub client_ca: Option<PathBuf>,
+    #[serde(default)]
+    pub client_required: bool, // refuse the clients without a certificate
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WebConfig {
+    pub listen: IpAddr, // checked when the config is loaded
+    pub port: u16,
+    pub tls: Option<TlsConfig>, // plain HTTP if none
+    pub auth: AuthConfig,
+}
+
+impl Default for WebConfig {
+    fn default() -> WebConfig {
+        WebConfig {
+            listen: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8091,
+            tls: None,
+            auth: AuthConfig::default(),
+        }
+    }
+}
+
+/// Application configuration, every section is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub can: CanConfig,
+    pub controller: ControllerConfig,
+    pub web: WebConfig,
+    #[cfg(feature = "mqtt")]
+    pub mqtt: Option<MqttConfig>, // the bridge runs if the section is present
+    pub rpc: Option<RpcConfig>, // the control socket is served if present
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let content = fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_addresses_are_checked() {
+        let config: Config = toml::from_str("[web]\nlisten = \"::1\"").unwrap();
+        assert_eq!(config.web.listen, "::1".parse::<IpAddr>().unwrap());
+
+        let err = toml::from_str::<Config>("[web]\nlisten = \"localhost:80\"").unwrap_err();
+        assert!(
+            err.to_string().contains("invalid IP address syntax"),
+            "{}",
+            err
+        );
+    }
+}
@@ -1,30 +1,45 @@
-use serde::Serialize;
-use std::{num::Wrapping, time::Duration};
+use async_trait::async_trait;
+use chrono::{Local, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    any::Any,
+    collections::{BTreeSet, VecDeque},
+    num::Wrapping,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 use tokio::{
     runtime::Runtime,
     select,
-    sync::{mpsc, oneshot},
-    time::sleep,
+    sync::{broadcast, mpsc, oneshot},
+    time::{interval, sleep, MissedTickBehavior},
 };
 
 use crate::{
-    alarm::{AlarmNode, AlarmAction},
+    action::{ActionSpec, DeviceNodeAction},
+    audit::{AuditConfig, AuditPage, AuditQuery, AuditRecord, AuditStore, Origin},
     can::{CanFrame, CanInterface, CanStats},
     device::{
-        Device, DeviceAction, DeviceActionTrait, DeviceControllableTrait, DeviceError,
-        DeviceHandle, DeviceTrait,
+        discovery_frame, parse_announce, Device, DeviceAction, DeviceActionResult,
+        DeviceActionTrait, DeviceControllableTrait, DeviceError, DeviceHandle, DeviceNodeTrait,
+        DeviceSnapshot, DeviceTrait,
     },
-    heater::{HeaterNode, HeaterAction},
+    event::{DeviceEvent, DeviceEventKind},
+    registry::{DeviceRegistry, RegistryError, TypeSettings},
+    rules::{self, Firing, Rule, RuleEngine, RuleError, RuleStatus},
     shutdown::Shutdown,
+    storage::{HistoryConfig, HistoryPage, HistoryQuery, HistoryStore, StateStore},
 };
 
-#[derive(Debug, Default, Serialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct ControllerStats {
     pub discovery_count: u32,
 }
 
 #[derive(Debug)]
-pub(crate) struct Controller {
+pub struct Controller {
     iface: CanInterface,
     stats: ControllerStats,
     config: ControllerConfig,
@@ -33,32 +48,151 @@ pub(crate) struct Controller {
     receiver: mpsc::Receiver<ControllerMessage>,
     handle: ControllerHandle,
 
-    dev_alarm: Device<AlarmNode>,
-    dev_heater: Device<HeaterNode>,
+    history: HistoryStore,
+    audit: AuditStore,
+    state: StateStore,
+    events: broadcast::Sender<DeviceEvent>,
+
+    registry: DeviceRegistry,
+    devices: Vec<Box<dyn DeviceNodeTrait>>,
+
+    rules: RuleEngine,
+    firings: VecDeque<Firing>, // rules fired, their actions still to run
 }
 
-#[derive(Debug)]
+/// Device known before discovery
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceConfig {
+    pub id: u32,
+    #[serde(rename = "type")]
+    pub type_name: String, // as registered, e.g. "alarm"
+    #[serde(default)]
+    pub settings: toml::Table, // over the [controller.<type>] settings
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
 pub struct ControllerConfig {
     pub discovery_period: u32, // in seconds
+    pub offline_timeout: u32,  // in seconds
+    pub state_path: Option<PathBuf>,
+    pub history: HistoryConfig,
+    pub audit: AuditConfig,
+    pub devices: Vec<DeviceConfig>,
+    pub rules: Vec<Rule>,
+    // settings of the device types, by type name, e.g. [controller.alarm]
+    #[serde(flatten)]
+    pub types: TypeSettings,
 }
 
 impl Default for ControllerConfig {
     fn default() -> ControllerConfig {
         ControllerConfig {
             discovery_period: 5,
+            offline_timeout: 60,
+            state_path: Some(PathBuf::from("state.json")),
+            history: HistoryConfig::default(),
+            audit: AuditConfig::default(),
+            devices: vec![
+                DeviceConfig {
+                    id: 1,
+                    type_name: "alarm".to_string(),
+                    settings: toml::Table::new(),
+                },
+                DeviceConfig {
+                    id: 2,
+                    type_name: "heater".to_string(),
+                    settings: toml::Table::new(),
+                },
+            ],
+            rules: Vec::new(),
+            types: TypeSettings::new(),
         }
     }
 }
 
+impl ControllerConfig {
+    /// Check the configured devices, type settings and rules, as done at
+    /// startup
+    pub fn check(&self, registry: &DeviceRegistry) -> Result<(), RegistryError> {
+        registry.check_settings(&self.types)?;
+
+        let mut ids = BTreeSet::new();
+        let mut devices = Vec::new();
+        for device in &self.devices {
+            devices.push(registry.create(
+                &device.type_name,
+                device.id,
+                &self.types,
+                &device.settings,
+            )?);
+            if !ids.insert(device.id) {
+                return Err(RegistryError::DuplicateId(device.id));
+            }
+        }
+
+        rules::check_rules(&self.rules, registry, &devices)?;
+
+        Ok(())
+    }
+}
+
+// Ticks between two applications of the history retention policy
+const HISTORY_PRUNE_PERIOD: u32 = 1800;
+
+// Events kept for the subscribers lagging behind
+const EVENTS_CAPACITY: usize = 64;
+
+// Ticks between two saves of the device states, e.g. energy counters
+const STATE_SAVE_PERIOD: u32 = 30;
+
+// Rules fired in a row, by the actions of each other, before giving up
+const RULES_MAX_FIRINGS: usize = 32;
+
+// State key of the rules changed through the API
+const RULES_KEY: &str = "rules";
+
 impl Controller {
     pub fn new(
         rt: &Runtime,
         iface: CanInterface,
         config: ControllerConfig,
+        registry: DeviceRegistry,
         shutdown: Shutdown,
     ) -> Controller {
         let (sender, receiver) = mpsc::channel(8);
 
+        let history = HistoryStore::open(config.history.clone()).unwrap_or_else(|err| {
+            println!(
+                "Failed to open history store ({}), keeping it in memory",
+                err
+            );
+            HistoryStore::open(HistoryConfig {
+                path: None,
+                ..config.history.clone()
+            })
+            .expect("In-memory history store")
+        });
+
+        let audit = AuditStore::open(config.audit.clone()).unwrap_or_else(|err| {
+            println!("Failed to open audit log ({}), keeping it in memory", err);
+            AuditStore::open(AuditConfig {
+                path: None,
+                ..config.audit.clone()
+            })
+            .expect("In-memory audit log")
+        });
+
+        let state = StateStore::open(config.state_path.clone()).unwrap_or_else(|err| {
+            println!("Failed to open state store ({}), keeping it in memory", err);
+            StateStore::default()
+        });
+
+        let rules = RuleEngine::new(
+            config.rules.clone(),
+            state.get(RULES_KEY).unwrap_or_default(),
+        );
+
         Controller {
             iface,
             stats: ControllerStats::default(),
@@ -66,14 +200,14 @@ impl Controller {
             shutdown,
             receiver,
             handle: ControllerHandle::new(rt, sender),
-            dev_alarm: Device::<AlarmNode> {
-                id: 1,
-                ..Default::default()
-            },
-            dev_heater: Device::<HeaterNode> {
-                id: 2,
-                ..Default::default()
-            },
+            history,
+            audit,
+            state,
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+            registry,
+            devices: Vec::new(),
+            rules,
+            firings: VecDeque::new(),
         }
     }
 
@@ -81,12 +215,69 @@ impl Controller {
         self.handle.clone()
     }
 
-    pub fn get_device_alarm_handle(&mut self) -> DeviceHandle {
-        DeviceHandle::from(&self.handle, &self.dev_alarm)
+    /// Add a device, its persisted state is restored
+    pub fn add_device<D>(&mut self, device: Device<D>) -> Result<DeviceHandle<D>, RegistryError>
+    where
+        D: DeviceTrait + DeviceControllableTrait + 'static,
+    {
+        let handle = DeviceHandle::from(&self.handle, &device);
+        self.insert_device(Box::new(device))?;
+        Ok(handle)
+    }
+
+    /// Add the devices of the config, built by the registry
+    pub fn add_configured_devices(&mut self) -> Result<(), RegistryError> {
+        self.registry.check_settings(&self.config.types)?;
+
+        for device in self.config.devices.clone() {
+            let node = self.registry.create(
+                &device.type_name,
+                device.id,
+                &self.config.types,
+                &device.settings,
+            )?;
+            self.insert_device(node)?;
+        }
+
+        // configured rules, and those changed through the API
+        rules::check_rules(&self.rules.rules(), &self.registry, &self.devices)?;
+
+        Ok(())
+    }
+
+    /// Typed handle of a device, None if unknown or not a `Device<D>`
+    pub fn device_handle<D>(&self, id: u32) -> Option<DeviceHandle<D>>
+    where
+        D: DeviceTrait + DeviceControllableTrait + 'static,
+    {
+        let device = self.devices.iter().find(|device| device.id() == id)?;
+        let device: &dyn Any = device.as_ref();
+        let device = device.downcast_ref::<Device<D>>()?;
+        Some(DeviceHandle::from(&self.handle, device))
     }
 
-    pub fn get_device_heater_handle(&mut self) -> DeviceHandle {
-        DeviceHandle::from(&self.handle, &self.dev_heater)
+    fn insert_device(&mut self, mut device: Box<dyn DeviceNodeTrait>) -> Result<(), RegistryError> {
+        if self.devices.iter().any(|d| d.id() == device.id()) {
+            return Err(RegistryError::DuplicateId(device.id()));
+        }
+
+        device.load_state(&self.state);
+        self.devices.push(device);
+        Ok(())
+    }
+
+    /// Add the device announcing itself, if its type is registered
+    fn handle_announce(&mut self, id: u32, code: u8) {
+        let name = self.registry.type_name(code).unwrap_or("unknown");
+        println!("Discovered device {} of type {} ({:#04x})", id, name, code);
+
+        let ret = self
+            .registry
+            .create_by_code(code, id, &self.config.types)
+            .and_then(|device| self.insert_device(device));
+        if let Err(err) = ret {
+            println!("Failed to add device {}: {}", id, err);
+        }
     }
 
     async fn handle_message(&mut self, message: ControllerMessage) {
@@ -103,26 +294,363 @@ impl Controller {
                     ControllerResponse::GetStats(self.stats.clone(), self.iface.stats.clone());
                 let _ = message.respond_to.send(response);
             }
-            ControllerMessageType::QueryDevice(action) => {
-                match action {
-                    DeviceNodeAction::Alarm(action) => {
-                        let z = self.dev_alarm.handle_action(&self.handle, &action).await;
+            ControllerMessageType::QueryDevice(id, action, origin) => {
+                let ret = self.run_action(id, &action).await;
+                self.record_action(origin, id, action.describe(), ret.as_ref().err());
+                let _ = message
+                    .respond_to
+                    .send(ControllerResponse::QueryDevice(ret));
+            }
+            ControllerMessageType::NamedAction(id, name, args, origin) => {
+                let ret = self.run_named_action(id, name, args, origin).await;
+                let _ = message
+                    .respond_to
+                    .send(ControllerResponse::QueryDevice(ret));
+            }
+            ControllerMessageType::GetActions(id) => {
+                let actions = self
+                    .devices
+                    .iter()
+                    .find(|device| device.id() == id)
+                    .map(|device| device.actions());
+                let _ = message
+                    .respond_to
+                    .send(ControllerResponse::GetActions(actions));
+            }
+            ControllerMessageType::DeviceAction(id, action, origin) => {
+                let ret = match self.devices.iter_mut().find(|device| device.id() == id) {
+                    Some(device) => {
+                        let mut bus = DeviceBus::new(&mut self.iface, id);
+                        Some(device.handle_device_action(&mut bus, action).await)
                     }
-                    DeviceNodeAction::Heater(action) => {
-                        let z = self.dev_heater.handle_action(&self.handle, &action).await;
+                    None => None,
+                };
+                if let Some(Err(err)) = &ret {
+                    println!("Device {} action {:?} failed: {}", id, action, err);
+                }
+                let err = match &ret {
+                    Some(ret) => ret.as_ref().err(),
+                    None => Some(&DeviceError::NotFound),
+                };
+                self.record_action(origin, id, action.describe(), err);
+                self.publish_events();
+                let _ = message
+                    .respond_to
+                    .send(ControllerResponse::DeviceAction(ret));
+            }
+            ControllerMessageType::Inspect(id, inspect) => {
+                let ret = self
+                    .devices
+                    .iter()
+                    .find(|device| device.id() == id)
+                    .map(|device| inspect(device.as_ref()));
+                let _ = message.respond_to.send(ControllerResponse::Inspect(ret));
+            }
+            ControllerMessageType::Subscribe => {
+                let receiver = self.events.subscribe();
+                let _ = message
+                    .respond_to
+                    .send(ControllerResponse::Subscribe(receiver));
+            }
+            ControllerMessageType::GetDevices => {
+                let devices = self
+                    .devices
+                    .iter()
+                    .map(|device| device.snapshot())
+                    .collect();
+                let _ = message
+                    .respond_to
+                    .send(ControllerResponse::GetDevices(devices));
+            }
+            ControllerMessageType::History(query) => {
+                let page = self.history.query(&query);
+                let _ = message.respond_to.send(ControllerResponse::History(page));
+            }
+            ControllerMessageType::Audit(query) => {
+                let page = self.audit.query(&query);
+                let _ = message.respond_to.send(ControllerResponse::Audit(page));
+            }
+            ControllerMessageType::GetRules => {
+                let rules = self.rules.statuses();
+                let _ = message.respond_to.send(ControllerResponse::GetRules(rules));
+            }
+            ControllerMessageType::SetRule(rule) => {
+                let ret = rule.check(&self.registry, &self.devices).map(|_| {
+                    println!("Rule {} set", rule.name);
+                    self.rules.set(rule);
+                    self.save_rules();
+                });
+                let _ = message.respond_to.send(ControllerResponse::SetRule(ret));
+            }
+            ControllerMessageType::DeleteRule(name) => {
+                let found = self.rules.remove(&name);
+                if found {
+                    println!("Rule {} removed", name);
+                    self.save_rules();
+                }
+                let _ = message
+                    .respond_to
+                    .send(ControllerResponse::DeleteRule(found));
+            }
+            ControllerMessageType::RecordAudit(record) => {
+                self.append_audit(record);
+                let _ = message.respond_to.send(ControllerResponse::RecordAudit);
+            }
+        }
+    }
+
+    /// Run an action by name, recorded in the audit log
+    async fn run_named_action(
+        &mut self,
+        id: u32,
+        name: String,
+        args: Value,
+        origin: Origin,
+    ) -> Result<(), DeviceError> {
+        let action = match self.devices.iter().find(|device| device.id() == id) {
+            Some(device) => device.parse_action(&name, args),
+            None => Err(DeviceError::NotFound),
+        };
+        match action {
+            Ok(action) => {
+                let ret = self.run_action(id, &action).await;
+                self.record_action(origin, id, action.describe(), ret.as_ref().err());
+                ret
+            }
+            Err(err) => {
+                // the arguments, possibly holding a code, are not recorded
+                let record = AuditRecord {
+                    timestamp: Utc::now(),
+                    origin,
+                    device_id: Some(id),
+                    action: name,
+                    args: Value::Null,
+                    error: Some(err.to_string()),
+                };
+                self.append_audit(record);
+                Err(err)
+            }
+        }
+    }
+
+    /// Run the actions of the rules fired, including the ones fired in turn
+    async fn run_rules(&mut self) {
+        let mut count = 0;
+        while let Some(firing) = self.firings.pop_front() {
+            count += 1;
+            if count > RULES_MAX_FIRINGS {
+                println!(
+                    "Rules fired more than {} times in a row, dropping {} firings",
+                    RULES_MAX_FIRINGS,
+                    self.firings.len() + 1
+                );
+                self.firings.clear();
+                break;
+            }
+
+            let snapshots = rules::snapshots(&self.devices);
+            for action in firing.actions {
+                for id in action.targets(&snapshots) {
+                    if firing.dry_run {
+                        println!(
+                            "Rule {} (dry run): would run {} on device {} with {}",
+                            firing.rule, action.action, id, action.args
+                        );
+                        continue;
+                    }
+
+                    println!(
+                        "Rule {}: running {} on device {}",
+                        firing.rule, action.action, id
+                    );
+                    let origin = Origin::Rule {
+                        name: firing.rule.clone(),
+                    };
+                    let ret = self
+                        .run_named_action(id, action.action.clone(), action.args.clone(), origin)
+                        .await;
+                    if let Err(err) = ret {
+                        println!(
+                            "Rule {} action {} failed: {}",
+                            firing.rule, action.action, err
+                        );
                     }
                 }
             }
         }
     }
 
+    fn save_rules(&mut self) {
+        if let Err(err) = self.state.set(RULES_KEY, self.rules.overrides()) {
+            println!("Failed to save the rules: {}", err);
+        }
+    }
+
+    /// Run an action on the device `id`, then persist its state
+    async fn run_action(&mut self, id: u32, action: &DeviceNodeAction) -> Result<(), DeviceError> {
+        let Some(device) = self.devices.iter_mut().find(|device| device.id() == id) else {
+            return Err(DeviceError::NotFound);
+        };
+
+        let mut ret = device
+            .handle_action(&mut DeviceBus::new(&mut self.iface, id), action)
+            .await;
+        if ret.is_ok() {
+            ret = device.save_state(&mut self.state).map_err(|err| {
+                println!("Failed to save device {} state: {}", id, err);
+                DeviceError::Storage
+            });
+        }
+        if let Err(err) = &ret {
+            println!("Device action failed: {}", err);
+        }
+        self.publish_events();
+
+        ret
+    }
+
+    /// Append an action and its outcome to the audit log
+    fn record_action(
+        &mut self,
+        origin: Origin,
+        device_id: u32,
+        (action, args): (&str, Value),
+        err: Option<&DeviceError>,
+    ) {
+        self.append_audit(AuditRecord {
+            timestamp: Utc::now(),
+            origin,
+            device_id: Some(device_id),
+            action: action.to_string(),
+            args,
+            error: err.map(|err| err.to_string()),
+        });
+    }
+
+    fn append_audit(&mut self, record: AuditRecord) {
+        if let Err(err) = self.audit.append(record) {
+            println!("Failed to record action in the audit log: {}", err);
+        }
+    }
+
+    fn save_states(&mut self) {
+        for device in &self.devices {
+            if let Err(err) = device.save_state(&mut self.state) {
+                println!("Failed to save device {} state: {}", device.id(), err);
+            }
+        }
+    }
+
     async fn handle_frame(&mut self, frame: CanFrame) -> Result<(), DeviceError> {
-        if frame.id == self.dev_alarm.id {
-            self.dev_alarm.handle_frame(&frame).await
-        } else if frame.id == self.dev_heater.id {
-            self.dev_heater.handle_frame(&frame).await
-        } else {
-            Ok(())
+        if self.history.config().record_frames {
+            self.record(DeviceEvent::new(
+                frame.id,
+                DeviceEventKind::Frame { data: frame.data },
+            ));
+        }
+
+        let ret = match self
+            .devices
+            .iter_mut()
+            .find(|device| device.accepts_frame(frame.id))
+        {
+            Some(device) => {
+                let id = device.id();
+                device
+                    .handle_frame(&mut DeviceBus::new(&mut self.iface, id), &frame)
+                    .await
+            }
+            None => {
+                if let Some(code) = parse_announce(&frame) {
+                    self.handle_announce(frame.id, code);
+                }
+                Ok(())
+            }
+        };
+
+        self.publish_events();
+
+        ret
+    }
+
+    /// Collect the events raised by the devices and record them
+    fn publish_events(&mut self) {
+        let mut events: Vec<DeviceEvent> = Vec::new();
+        for device in &mut self.devices {
+            let id = device.id();
+            events.extend(
+                device
+                    .take_events()
+                    .into_iter()
+                    .map(|kind| DeviceEvent::new(id, kind)),
+            );
+        }
+
+        // the rules see the states after the events
+        let snapshots = match self.rules.is_empty() || events.is_empty() {
+            true => None,
+            false => Some(rules::snapshots(&self.devices)),
+        };
+        for event in events {
+            if let Some(snapshots) = &snapshots {
+                let firings = self.rules.on_event(&event, snapshots);
+                self.firings.extend(firings);
+            }
+            self.record(event);
+        }
+        if let Some(snapshots) = &snapshots {
+            let firings = self.rules.on_state(snapshots);
+            self.firings.extend(firings);
+        }
+    }
+
+    fn record(&mut self, event: DeviceEvent) {
+        println!("Event: {:?}", event);
+        // no subscriber is not an error
+        let _ = self.events.send(event.clone());
+        if let Err(err) = self.history.append(event) {
+            println!("Failed to record event: {}", err);
+        }
+    }
+
+    async fn tick(&mut self, counter: u32) {
+        let timeout = Duration::from_secs(self.config.offline_timeout as u64);
+        let mut scheduled = Vec::new();
+        for device in &mut self.devices {
+            let id = device.id();
+            device.check_presence(timeout);
+            if let Err(err) = device.tick(&mut DeviceBus::new(&mut self.iface, id)).await {
+                println!("Device {} tick failed: {}", id, err);
+            }
+            scheduled.extend(
+                device
+                    .take_scheduled()
+                    .into_iter()
+                    .map(|action| (id, action)),
+            );
+        }
+        for (id, (action, result)) in scheduled {
+            self.record_action(Origin::Schedule, id, action, result.err().as_ref());
+        }
+
+        self.publish_events();
+
+        if !self.rules.is_empty() {
+            let snapshots = rules::snapshots(&self.devices);
+            let firings = self.rules.on_tick(Local::now(), &snapshots);
+            self.firings.extend(firings);
+            let firings = self.rules.on_state(&snapshots);
+            self.firings.extend(firings);
+        }
+
+        if counter.is_multiple_of(HISTORY_PRUNE_PERIOD) {
+            if let Err(err) = self.history.prune() {
+                println!("Failed to prune history: {}", err);
+            }
+        }
+
+        if counter.is_multiple_of(STATE_SAVE_PERIOD) {
+            self.save_states();
         }
     }
 
@@ -133,15 +661,12 @@ impl Controller {
         );
         self.stats.discovery_count += 1;
 
-        // let discovery_frame = CanFrame {
-        //     id: 0x123,
-        //     data: [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08],
-        // };
-
-        // self.iface.send(discovery_frame).await;
+        // unknown nodes answering are added by handle_frame
+        self.iface.send(discovery_frame()).await;
 
         if let Some(frame) = self.iface.recv(true).await {
             println!("Received frame: {:?}", frame);
+            self.iface.requeue(frame);
         }
     }
 
@@ -149,7 +674,7 @@ impl Controller {
         println!("Querying device: {} timeout {:?}", id, timeout_ms);
 
         let query = CanFrame {
-            id: id,
+            id,
             data: [0xFF; 8],
         };
         self.iface.send(query).await;
@@ -161,21 +686,43 @@ impl Controller {
     }
 }
 
-pub enum DeviceNodeAction {
-    Alarm(AlarmAction),
-    Heater(HeaterAction),
-}
+/// Function run on a device from within the controller task, see `inspect`
+type InspectFn = Box<dyn FnOnce(&dyn DeviceNodeTrait) -> Box<dyn Any + Send> + Send>;
 
 pub enum ControllerMessageType {
     Query(u32, Option<u32>), // id, timeout_ms
     GetStats,
-    QueryDevice(DeviceNodeAction),
+    QueryDevice(u32, DeviceNodeAction, Origin),
+    NamedAction(u32, String, Value, Origin), // device id, action name, arguments
+    GetActions(u32),
+    DeviceAction(u32, DeviceAction, Origin), // management action, by device id
+    Inspect(u32, InspectFn),
+    Subscribe,
+    GetDevices,
+    History(HistoryQuery),
+    Audit(AuditQuery),
+    GetRules,
+    SetRule(Rule),
+    DeleteRule(String),
+    RecordAudit(AuditRecord), // e.g. a denied request
 }
 
 #[derive(Debug)]
 pub enum ControllerResponse {
     Query(u32),
     GetStats(ControllerStats, CanStats),
+    QueryDevice(Result<(), DeviceError>),
+    GetActions(Option<Vec<ActionSpec>>), // None if unknown device
+    DeviceAction(Option<Result<DeviceActionResult, DeviceError>>), // None if unknown device
+    Inspect(Option<Box<dyn Any + Send>>), // None if unknown device
+    Subscribe(broadcast::Receiver<DeviceEvent>),
+    GetDevices(Vec<DeviceSnapshot>),
+    History(HistoryPage),
+    Audit(AuditPage),
+    GetRules(Vec<RuleStatus>),
+    SetRule(Result<(), RuleError>),
+    DeleteRule(bool), // false if unknown
+    RecordAudit,
 }
 
 pub struct ControllerMessage {
@@ -183,9 +730,11 @@ pub struct ControllerMessage {
     inner: ControllerMessageType,
 }
 
-pub(crate) async fn run_controller(mut ctrl: Controller) {
+pub async fn run_controller(mut ctrl: Controller) {
     let mut counter: Wrapping<u32> = Wrapping(0);
     let mut last_discovery: u32 = 0;
+    let mut ticks = interval(Duration::from_secs(2));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
     loop {
         select! {
             Some(msg) = ctrl.receiver.recv() => {
@@ -194,11 +743,14 @@ pub(crate) async fn run_controller(mut ctrl: Controller) {
             },
             Some(msg) = ctrl.iface.recv(false) => {
                 println!("Received frame: {:?}", msg);
-                ctrl.handle_frame(msg).await;
+                if let Err(err) = ctrl.handle_frame(msg).await {
+                    println!("Failed to handle frame: {}", err);
+                }
             },
-            _ = sleep(Duration::from_secs(2)) => {
+            _ = ticks.tick() => {
                 println!("Tick");
                 counter += 1;
+                ctrl.tick(counter.0).await;
                 if counter.0 - last_discovery > ctrl.config.discovery_period {
                     ctrl.discover().await;
                     last_discovery = counter.0;
@@ -206,9 +758,11 @@ pub(crate) async fn run_controller(mut ctrl: Controller) {
             },
             _ = ctrl.shutdown.recv() => {
                 println!("Shutting down controller");
+                ctrl.save_states();
                 break;
             }
         }
+        ctrl.run_rules().await;
     }
 }
 
@@ -218,7 +772,7 @@ pub struct ControllerHandle {
 }
 
 impl ControllerHandle {
-    pub fn new(rt: &Runtime, sender: mpsc::Sender<ControllerMessage>) -> Self {
+    pub fn new(_rt: &Runtime, sender: mpsc::Sender<ControllerMessage>) -> Self {
         Self { sender }
     }
 
@@ -241,7 +795,7 @@ impl ControllerHandle {
         }
     }
 
-    pub async fn get_stats(&self) -> ControllerStats {
+    pub async fn get_stats(&self) -> (ControllerStats, CanStats) {
         let (send, recv) = oneshot::channel();
         let msg = ControllerMessage {
             respond_to: send,
@@ -255,47 +809,389 @@ impl ControllerHandle {
         let answer = recv.await.expect("Controller task has been killed");
 
         match answer {
-            ControllerResponse::GetStats(stats, _) => stats,
+            ControllerResponse::GetStats(stats, can_stats) => (stats, can_stats),
             _ => panic!("Unexpected response"),
         }
     }
 
-    pub async fn query_device(&self, action: DeviceNodeAction) {
+    pub async fn get_devices(&self) -> Vec<DeviceSnapshot> {
+        let (send, recv) = oneshot::channel();
         let msg = ControllerMessage {
-            respond_to: oneshot::channel().0,
-            inner: ControllerMessageType::QueryDevice(action),
+            respond_to: send,
+            inner: ControllerMessageType::GetDevices,
         };
 
         // Ignore send errors. If this send fails, so does the
         // recv.await below. There's no reason to check the
         // failure twice.
         let _ = self.sender.send(msg).await;
+        let answer = recv.await.expect("Controller task has been killed");
+
+        match answer {
+            ControllerResponse::GetDevices(devices) => devices,
+            _ => panic!("Unexpected response"),
+        }
     }
 
-    pub async fn device_handle_action<A: DeviceActionTrait>(
-        &mut self,
-        dev: &mut dyn DeviceControllableTrait<Action = A>,
-        action: &A,
+    pub async fn get_history(&self, query: HistoryQuery) -> HistoryPage {
+        let (send, recv) = oneshot::channel();
+        let msg = ControllerMessage {
+            respond_to: send,
+            inner: ControllerMessageType::History(query),
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check the
+        // failure twice.
+        let _ = self.sender.send(msg).await;
+        let answer = recv.await.expect("Controller task has been killed");
+
+        match answer {
+            ControllerResponse::History(page) => page,
+            _ => panic!("Unexpected response"),
+        }
+    }
+
+    /// Rules and their counters
+    pub async fn get_rules(&self) -> Vec<RuleStatus> {
+        let (send, recv) = oneshot::channel();
+        let msg = ControllerMessage {
+            respond_to: send,
+            inner: ControllerMessageType::GetRules,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check the
+        // failure twice.
+        let _ = self.sender.send(msg).await;
+        let answer = recv.await.expect("Controller task has been killed");
+
+        match answer {
+            ControllerResponse::GetRules(rules) => rules,
+            _ => panic!("Unexpected response"),
+        }
+    }
+
+    /// Add or replace a rule, by name, saved in the state store
+    pub async fn set_rule(&self, rule: Rule) -> Result<(), RuleError> {
+        let (send, recv) = oneshot::channel();
+        let msg = ControllerMessage {
+            respond_to: send,
+            inner: ControllerMessageType::SetRule(rule),
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check the
+        // failure twice.
+        let _ = self.sender.send(msg).await;
+        let answer = recv.await.expect("Controller task has been killed");
+
+        match answer {
+            ControllerResponse::SetRule(ret) => ret,
+            _ => panic!("Unexpected response"),
+        }
+    }
+
+    /// Remove a rule, false if unknown
+    pub async fn delete_rule(&self, name: String) -> bool {
+        let (send, recv) = oneshot::channel();
+        let msg = ControllerMessage {
+            respond_to: send,
+            inner: ControllerMessageType::DeleteRule(name),
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check the
+        // failure twice.
+        let _ = self.sender.send(msg).await;
+        let answer = recv.await.expect("Controller task has been killed");
+
+        match answer {
+            ControllerResponse::DeleteRule(found) => found,
+            _ => panic!("Unexpected response"),
+        }
+    }
+
+    /// Append a record to the audit log, for the actions denied before
+    /// reaching the controller
+    pub async fn record_audit(&self, record: AuditRecord) {
+        let (send, recv) = oneshot::channel();
+        let msg = ControllerMessage {
+            respond_to: send,
+            inner: ControllerMessageType::RecordAudit(record),
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check the
+        // failure twice.
+        let _ = self.sender.send(msg).await;
+        let answer = recv.await.expect("Controller task has been killed");
+
+        match answer {
+            ControllerResponse::RecordAudit => (),
+            _ => panic!("Unexpected response"),
+        }
+    }
+
+    /// Recorded device actions, see `ControllerConfig::audit`
+    pub async fn get_audit(&self, query: AuditQuery) -> AuditPage {
+        let (send, recv) = oneshot::channel();
+        let msg = ControllerMessage {
+            respond_to: send,
+            inner: ControllerMessageType::Audit(query),
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check the
+        // failure twice.
+        let _ = self.sender.send(msg).await;
+        let answer = recv.await.expect("Controller task has been killed");
+
+        match answer {
+            ControllerResponse::Audit(page) => page,
+            _ => panic!("Unexpected response"),
+        }
+    }
+
+    /// Run an action, recorded in the audit log along with its `origin`
+    pub async fn query_device(
+        &self,
+        id: u32,
+        action: DeviceNodeAction,
+        origin: Origin,
+    ) -> Result<(), DeviceError> {
+        let (send, recv) = oneshot::channel();
+        let msg = ControllerMessage {
+            respond_to: send,
+            inner: ControllerMessageType::QueryDevice(id, action, origin),
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check the
+        // failure twice.
+        let _ = self.sender.send(msg).await;
+        let answer = recv.await.expect("Controller task has been killed");
+
+        match answer {
+            ControllerResponse::QueryDevice(ret) => ret,
+            _ => panic!("Unexpected response"),
+        }
+    }
+
+    /// Run an action by name, as listed by `get_actions`
+    pub async fn named_action(
+        &self,
+        id: u32,
+        name: String,
+        args: Value,
+        origin: Origin,
     ) -> Result<(), DeviceError> {
-        dev.handle_action(self, action).await
+        let (send, recv) = oneshot::channel();
+        let msg = ControllerMessage {
+            respond_to: send,
+            inner: ControllerMessageType::NamedAction(id, name, args, origin),
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check the
+        // failure twice.
+        let _ = self.sender.send(msg).await;
+        let answer = recv.await.expect("Controller task has been killed");
+
+        match answer {
+            ControllerResponse::QueryDevice(ret) => ret,
+            _ => panic!("Unexpected response"),
+        }
+    }
+
+    /// Events of all the devices, from now on
+    pub async fn subscribe(&self) -> broadcast::Receiver<DeviceEvent> {
+        let (send, recv) = oneshot::channel();
+        let msg = ControllerMessage {
+            respond_to: send,
+            inner: ControllerMessageType::Subscribe,
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check the
+        // failure twice.
+        let _ = self.sender.send(msg).await;
+        let answer = recv.await.expect("Controller task has been killed");
+
+        match answer {
+            ControllerResponse::Subscribe(receiver) => receiver,
+            _ => panic!("Unexpected response"),
+        }
+    }
+
+    pub async fn get_actions(&self, id: u32) -> Option<Vec<ActionSpec>> {
+        let (send, recv) = oneshot::channel();
+        let msg = ControllerMessage {
+            respond_to: send,
+            inner: ControllerMessageType::GetActions(id),
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check the
+        // failure twice.
+        let _ = self.sender.send(msg).await;
+        let answer = recv.await.expect("Controller task has been killed");
+
+        match answer {
+            ControllerResponse::GetActions(actions) => actions,
+            _ => panic!("Unexpected response"),
+        }
+    }
+
+    /// Read the device `id` with `f`, None if unknown or not a `Device<D>`
+    pub async fn inspect<D, R, F>(&self, id: u32, f: F) -> Option<R>
+    where
+        D: DeviceTrait + 'static,
+        R: Send + 'static,
+        F: FnOnce(&Device<D>) -> R + Send + 'static,
+    {
+        let inspect: InspectFn = Box::new(move |device: &dyn DeviceNodeTrait| {
+            let device: &dyn Any = device;
+            Box::new(device.downcast_ref::<Device<D>>().map(f))
+        });
+
+        let (send, recv) = oneshot::channel();
+        let msg = ControllerMessage {
+            respond_to: send,
+            inner: ControllerMessageType::Inspect(id, inspect),
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check the
+        // failure twice.
+        let _ = self.sender.send(msg).await;
+        let answer = recv.await.expect("Controller task has been killed");
+
+        match answer {
+            ControllerResponse::Inspect(ret) => ret
+                .and_then(|ret| ret.downcast::<Option<R>>().ok())
+                .and_then(|ret| *ret),
+            _ => panic!("Unexpected response"),
+        }
+    }
+
+    pub async fn device_action(
+        &self,
+        id: u32,
+        action: DeviceAction,
+        origin: Origin,
+    ) -> Option<Result<DeviceActionResult, DeviceError>> {
+        let (send, recv) = oneshot::channel();
+        let msg = ControllerMessage {
+            respond_to: send,
+            inner: ControllerMessageType::DeviceAction(id, action, origin),
+        };
+
+        // Ignore send errors. If this send fails, so does the
+        // recv.await below. There's no reason to check the
+        // failure twice.
+        let _ = self.sender.send(msg).await;
+        let answer = recv.await.expect("Controller task has been killed");
+
+        match answer {
+            ControllerResponse::DeviceAction(ret) => ret,
+            _ => panic!("Unexpected response"),
+        }
     }
 }
 
+/// Bus access given to the devices while they run.
+///
+/// Devices are driven from within the controller task, so this must not go
+/// through the `ControllerHandle` channel: the controller would wait for itself.
 #[async_trait]
-pub trait ControllerAPI: Send + Sync {
-    async fn query_frame(&self, id: u32, timeout_ms: Option<u32>) -> u32;
+pub trait ControllerAPI: Send {
+    /// Send a command frame to the device being run
+    async fn command(&mut self, data: [u8; 8]);
+    /// Send a command frame and wait for the device frame accepted by `is_ack`
+    async fn request(
+        &mut self,
+        data: [u8; 8],
+        is_ack: &(dyn for<'f> Fn(&'f CanFrame) -> bool + Sync),
+        timeout: Duration,
+    ) -> Result<CanFrame, DeviceError>;
+    async fn query_frame(&mut self, id: u32, timeout_ms: Option<u32>) -> u32;
 }
 
-#[async_trait]
-impl ControllerAPI for ControllerHandle {
-    async fn query_frame(&self, id: u32, timeout_ms: Option<u32>) -> u32 {
-        ControllerHandle::query(self, id, timeout_ms).await
+const REQUEST_POLL_PERIOD: Duration = Duration::from_millis(50);
+/// Frames kept for the controller while waiting for an acknowledgement
+const REQUEST_MAX_PENDING: usize = 64;
+
+/// `ControllerAPI` implementation, scoped to a single device
+pub struct DeviceBus<'a> {
+    iface: &'a mut CanInterface,
+    id: u32,
+}
+
+impl<'a> DeviceBus<'a> {
+    fn new(iface: &'a mut CanInterface, id: u32) -> DeviceBus<'a> {
+        DeviceBus { iface, id }
     }
 }
 
 #[async_trait]
-impl ControllerAPI for Controller {
-    async fn query_frame(&self, id: u32, timeout_ms: Option<u32>) -> u32 {
-        self.handle.query(id, timeout_ms).await
+impl ControllerAPI for DeviceBus<'_> {
+    async fn command(&mut self, data: [u8; 8]) {
+        self.iface.send(CanFrame { id: self.id, data }).await
+    }
+
+    async fn request(
+        &mut self,
+        data: [u8; 8],
+        is_ack: &(dyn for<'f> Fn(&'f CanFrame) -> bool + Sync),
+        timeout: Duration,
+    ) -> Result<CanFrame, DeviceError> {
+        self.command(data).await;
+
+        let deadline = Instant::now() + timeout;
+        let mut others = Vec::new();
+        let ret = loop {
+            // checked first, so that a busy bus cannot hold the request past it
+            if Instant::now() >= deadline {
+                break Err(DeviceError::Timeout);
+            }
+            if let Some(frame) = self.iface.recv(true).await {
+                if frame.id == self.id && is_ack(&frame) {
+                    break Ok(frame);
+                }
+                // our own command, looped back by the bus
+                if frame.id == self.id && frame.data == data {
+                    continue;
+                }
+                if others.len() < REQUEST_MAX_PENDING {
+                    others.push(frame);
+                } else {
+                    println!("Dropped frame during request: {:?}", frame);
+                }
+            } else {
+                sleep(REQUEST_POLL_PERIOD).await;
+            }
+        };
+
+        // frames for other purposes are handled by the controller afterwards
+        for frame in others {
+            self.iface.requeue(frame);
+        }
+
+        ret
+    }
+
+    async fn query_frame(&mut self, id: u32, _timeout_ms: Option<u32>) -> u32 {
+        let query = CanFrame {
+            id,
+            data: [0xFF; 8],
+        };
+        self.iface.send(query).await;
+
+        self.iface
+            .recv(true)
+            .await
+            .map(|frame| frame.data[0] as u32)
+            .unwrap_or(0)
     }
-}
\ No newline at end of file
+}
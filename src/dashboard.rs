@@ -0,0 +1,27 @@
+//! Single-page dashboard served at `/`, its assets embedded in the binary.
+//! It only uses the REST API, `/events` for the live updates.
+use rocket::http::ContentType;
+use rocket::response::content::RawHtml;
+use rocket::Route;
+
+const INDEX: &str = include_str!("dashboard/index.html");
+const APP: &str = include_str!("dashboard/app.js");
+const STYLE: &str = include_str!("dashboard/style.css");
+
+#[get("/")]
+fn route_index() -> RawHtml<&'static str> {
+    RawHtml(INDEX)
+}
+
+#[get("/dashboard/<file>")]
+fn route_asset(file: &str) -> Option<(ContentType, &'static str)> {
+    match file {
+        "app.js" => Some((ContentType::JavaScript, APP)),
+        "style.css" => Some((ContentType::CSS, STYLE)),
+        _ => None,
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![route_index, route_asset]
+}
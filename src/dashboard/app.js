@@ -0,0 +1,228 @@
+"use strict";
+
+const HEATER_STATES = ["off", "comfort", "eco", "anti_freeze"];
+const ZONE_NAMES = ["Left", "Right"];
+const MAX_EVENTS = 200;
+const STATS_PERIOD_MS = 2000;
+const DEVICES_PERIOD_MS = 10000;
+
+const ERRORS = {
+  400: "Invalid action",
+  401: "Authentication required",
+  403: "Forbidden: role too low, or alarm code required or invalid",
+  404: "Unknown device",
+  409: "Not allowed in the current state",
+  429: "Alarm codes locked out",
+  504: "The node did not answer",
+};
+
+function el(tag, attrs, ...children) {
+  const node = document.createElement(tag);
+  for (const [name, value] of Object.entries(attrs || {})) {
+    if (name.startsWith("on")) {
+      node.addEventListener(name.slice(2), value);
+    } else {
+      node[name] = value;
+    }
+  }
+  for (const child of children) {
+    node.append(child);
+  }
+  return node;
+}
+
+function setStatus(text) {
+  document.getElementById("status").textContent = text;
+}
+
+async function getJson(path) {
+  const response = await fetch(path, { headers: { Accept: "application/json" } });
+  if (!response.ok) {
+    throw new Error(ERRORS[response.status] || response.statusText);
+  }
+  return response.json();
+}
+
+async function runAction(id, name, args) {
+  setStatus("");
+  const response = await fetch(`/devices/${id}/actions/${name}`, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify(args),
+  });
+  if (!response.ok) {
+    setStatus(`${name}: ${ERRORS[response.status] || response.statusText}`);
+  }
+  refreshDevices();
+}
+
+function withCode(args) {
+  const code = document.getElementById("code").value;
+  return code ? { ...args, code } : args;
+}
+
+function deviceState(device) {
+  switch (device.type) {
+    case "alarm":
+      return `${device.state} (${device.mode})`;
+    case "heater":
+      return (device.active ? "active, " : "inactive, ") +
+        device.zones.map((zone) => zone.state).join(" / ");
+    default:
+      return "-";
+  }
+}
+
+function renderDevices(devices) {
+  const rows = devices.map((device) => el("tr", {},
+    el("td", {}, String(device.id)),
+    el("td", {}, device.type),
+    el("td", { className: device.online ? "online" : "offline" }, device.online ? "yes" : "no"),
+    el("td", {}, device.last_seen_secs == null ? "never" : `${device.last_seen_secs}s ago`),
+    el("td", {}, device.version || "-"),
+    el("td", {}, deviceState(device)),
+  ));
+  document.getElementById("devices").replaceChildren(...rows);
+}
+
+function alarmCard(alarm) {
+  const lights = (front, rear) => runAction(alarm.id, "power_lights", { front, rear });
+  return el("div", { className: "card" },
+    el("h3", {}, `Alarm ${alarm.id}: ${alarm.state}`),
+    el("div", { className: "row" },
+      el("button", { onclick: () => runAction(alarm.id, "arm", withCode({ mode: "away" })) }, "Arm away"),
+      el("button", { onclick: () => runAction(alarm.id, "arm", withCode({ mode: "stay" })) }, "Arm stay"),
+      el("button", { onclick: () => runAction(alarm.id, "disarm", withCode({})) }, "Disarm"),
+    ),
+    el("div", { className: "row" },
+      "Lights",
+      el("label", {},
+        el("input", {
+          type: "checkbox",
+          checked: alarm.lights.front,
+          onchange: (event) => lights(event.target.checked, alarm.lights.rear),
+        }),
+        " front"),
+      el("label", {},
+        el("input", {
+          type: "checkbox",
+          checked: alarm.lights.rear,
+          onchange: (event) => lights(alarm.lights.front, event.target.checked),
+        }),
+        " rear"),
+    ),
+  );
+}
+
+// state asked for a zone, put aside while its window is open
+const wanted = (zone) => zone.saved_request ?? zone.requested;
+
+function heaterCard(heater) {
+  const zones = heater.zones.map((zone, index) => {
+    const select = el("select", {
+      onchange: (event) => {
+        const states = heater.zones.map(wanted);
+        states[index] = event.target.value;
+        runAction(heater.id, "heater_power", { left: states[0], right: states[1] });
+      },
+    }, ...HEATER_STATES.map((state) => el("option", { value: state, selected: state === wanted(zone) }, state)));
+    const temperature = zone.temperature == null ? "-" : `${zone.temperature.toFixed(1)} °C`;
+    const setpoint = zone.setpoint == null ? "-" : `${zone.setpoint.toFixed(1)} °C`;
+    return el("div", { className: "row" },
+      ZONE_NAMES[index] || `Zone ${index}`, select,
+      `${zone.state}, ${temperature} / ${setpoint}`,
+      zone.output ? "heating" : "idle",
+      zone.window_open ? "window open" : "",
+    );
+  });
+  return el("div", { className: "card" },
+    el("h3", {}, `Heater ${heater.id}`),
+    el("div", { className: "row" },
+      el("label", {},
+        el("input", {
+          type: "checkbox",
+          checked: heater.active,
+          onchange: (event) => runAction(heater.id, "set_active", { active: event.target.checked }),
+        }),
+        " active"),
+    ),
+    ...zones,
+  );
+}
+
+function renderControls(devices) {
+  const cards = devices.flatMap((device) => {
+    switch (device.type) {
+      case "alarm":
+        return [alarmCard(device)];
+      case "heater":
+        return [heaterCard(device)];
+      default:
+        return [];
+    }
+  });
+  document.getElementById("controls").replaceChildren(...cards);
+}
+
+let refreshTimer = null;
+
+// debounced, events often come in bursts
+function refreshDevices() {
+  clearTimeout(refreshTimer);
+  refreshTimer = setTimeout(async () => {
+    try {
+      const devices = await getJson("/devices");
+      renderDevices(devices);
+      renderControls(devices);
+    } catch (err) {
+      setStatus(`Devices: ${err.message}`);
+    }
+  }, 200);
+}
+
+async function refreshStats() {
+  try {
+    const [stats, can] = await Promise.all([getJson("/stats"), getJson("/stats/can")]);
+    document.getElementById("stat-rx").textContent = can.rx;
+    document.getElementById("stat-tx").textContent = can.tx;
+    document.getElementById("stat-discovery").textContent = stats.discovery_count;
+  } catch (err) {
+    setStatus(`Stats: ${err.message}`);
+  }
+}
+
+function addEvent(event) {
+  const { timestamp, device_id, kind, ...fields } = event;
+  const details = Object.entries(fields)
+    .map(([name, value]) => `${name}=${JSON.stringify(value)}`)
+    .join(" ");
+  const time = new Date(timestamp).toLocaleTimeString();
+  const list = document.getElementById("events");
+  list.prepend(el("li", {}, `${time} #${device_id} ${kind} ${details}`));
+  while (list.children.length > MAX_EVENTS) {
+    list.lastChild.remove();
+  }
+}
+
+function subscribe() {
+  const stream = document.getElementById("stream");
+  const source = new EventSource("/events");
+  source.onopen = () => {
+    stream.textContent = "events live";
+    stream.className = "online";
+  };
+  source.onerror = () => {
+    stream.textContent = "events offline";
+    stream.className = "offline";
+  };
+  source.onmessage = (message) => {
+    addEvent(JSON.parse(message.data));
+    refreshDevices();
+  };
+}
+
+refreshDevices();
+refreshStats();
+subscribe();
+setInterval(refreshStats, STATS_PERIOD_MS);
+setInterval(refreshDevices, DEVICES_PERIOD_MS);
@@ -1,16 +1,95 @@
 use async_trait::async_trait;
-use std::{fmt::Debug, time::Instant};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    any::Any,
+    fmt::Debug,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
 use thiserror::Error;
+use tokio::sync::broadcast;
 
 use crate::{
+    action::{ActionArgs, ActionSpec, ArgKind, DeviceNodeAction},
+    alarm::AlarmSnapshot,
+    audit::Origin,
     can::CanFrame,
     controller::{ControllerAPI, ControllerHandle},
+    event::{DeviceEvent, DeviceEventKind},
+    heater::HeaterSnapshot,
+    storage::{StateStore, StorageError},
 };
 
-#[derive(Error, Debug)]
+// Management commands, understood by every node type and acknowledged with
+// [command | MGMT_ACK, ...]:
+// - reset: [MGMT_RESET, 0, 0, 0, 0, 0, 0, 0], acknowledged before rebooting
+// - identify: [MGMT_IDENTIFY, duration (s), 0, 0, 0, 0, 0, 0], blinks the LED
+// - ping: [MGMT_PING, 0, 0, 0, 0, 0, 0, 0]
+// - version: [MGMT_VERSION, 0, ...], answered with [.., major, minor, patch, ...]
+// - announce: [MGMT_ANNOUNCE, 0, ...], broadcast to DISCOVERY_ID, every node
+//   answers with [.., type code, 0, ...] from its own id
+const MGMT_RESET: u8 = 0x70;
+const MGMT_IDENTIFY: u8 = 0x71;
+const MGMT_PING: u8 = 0x72;
+const MGMT_VERSION: u8 = 0x73;
+const MGMT_ANNOUNCE: u8 = 0x74;
+const MGMT_ACK: u8 = 0x80;
+const MGMT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// LED blink duration of the identify action, unless given
+pub const IDENTIFY_DEFAULT_SECS: u8 = 10;
+
+#[derive(Error, Debug, Clone)]
 pub enum DeviceError {
-    #[error("Device timed out")]
+    #[error("Unsupported action")]
     Unsupported,
+    #[error("Device timed out")]
+    Timeout,
+    #[error("Malformed frame")]
+    InvalidFrame,
+    #[error("Failed to persist the device state")]
+    Storage,
+    #[error("Action not allowed in the current state")]
+    InvalidState,
+    #[error("A user code is required")]
+    CodeRequired,
+    #[error("Invalid user code")]
+    InvalidCode,
+    #[error("Too many failed attempts, try again later")]
+    LockedOut,
+    #[error("Unknown device")]
+    NotFound,
+    #[error("Invalid action arguments")]
+    InvalidArgs,
+}
+
+/// Action a device took on its own, as `DeviceActionTrait::describe`, with
+/// its outcome
+pub type ScheduledAction = ((&'static str, Value), Result<(), DeviceError>);
+
+/// State of a device, as reported to the API clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DeviceSnapshot {
+    pub id: u32,
+    pub online: bool,
+    pub last_seen_secs: Option<u64>, // seconds since the last frame
+    pub version: Option<String>,     // firmware version, once read
+
+    #[serde(flatten)]
+    pub node: NodeSnapshot,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NodeSnapshot {
+    Alarm(AlarmSnapshot),
+    Heater(HeaterSnapshot),
+    // out-of-tree device types, the object should have its own "type" field
+    #[serde(untagged)]
+    Other(serde_json::Value),
 }
 
 #[derive(Debug, Default)]
@@ -20,8 +99,12 @@ where
 {
     pub id: u32,
     pub last_seen: Option<Instant>,
+    pub online: bool,
+    pub version: Option<(u8, u8, u8)>,
 
     pub specific: D,
+
+    events: Vec<DeviceEventKind>,
 }
 
 #[async_trait]
@@ -29,10 +112,41 @@ impl<D> DeviceTrait for Device<D>
 where
     D: DeviceTrait,
 {
-    async fn handle_frame(&mut self, frame: &CanFrame) -> Result<(), DeviceError> {
+    async fn handle_frame(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        frame: &CanFrame,
+    ) -> Result<(), DeviceError> {
         self.last_seen = Some(Instant::now());
+        if !self.online {
+            self.online = true;
+            self.events.push(DeviceEventKind::Online);
+        }
+
+        // management frames are not part of the node's own protocol, e.g. a
+        // late acknowledgement of a timed-out request
+        if is_management(frame.data[0]) {
+            if frame.data[0] == MGMT_VERSION | MGMT_ACK {
+                self.version = Some((frame.data[1], frame.data[2], frame.data[3]));
+            }
+            return Ok(());
+        }
+
+        self.specific.handle_frame(api, frame).await
+    }
+
+    async fn tick(&mut self, api: &mut dyn ControllerAPI) -> Result<(), DeviceError> {
+        self.specific.tick(api).await
+    }
 
-        self.specific.handle_frame(frame).await
+    fn snapshot(&self) -> NodeSnapshot {
+        self.specific.snapshot()
+    }
+
+    fn take_events(&mut self) -> Vec<DeviceEventKind> {
+        let mut events = std::mem::take(&mut self.events);
+        events.append(&mut self.specific.take_events());
+        events
     }
 
     // async fn handle_action(&mut self, action: &dyn DeviceAction<Self>) -> Result<(), DeviceError> {
@@ -44,38 +158,156 @@ impl<D> Device<D>
 where
     D: DeviceTrait,
 {
+    pub fn new(id: u32) -> Device<D> {
+        Device {
+            id,
+            ..Default::default()
+        }
+    }
+
+    pub fn snapshot(&self) -> DeviceSnapshot {
+        DeviceSnapshot {
+            id: self.id,
+            online: self.online,
+            last_seen_secs: self.last_seen.map(|last| last.elapsed().as_secs()),
+            version: self
+                .version
+                .map(|(major, minor, patch)| format!("{}.{}.{}", major, minor, patch)),
+            node: self.specific.snapshot(),
+        }
+    }
+
+    pub fn with(id: u32, specific: D) -> Device<D> {
+        Device {
+            id,
+            specific,
+            ..Default::default()
+        }
+    }
+
     fn get_id(&self) -> u32 {
         self.id
     }
+
+    /// Mark the device offline if it has not been seen for `timeout`
+    pub fn check_presence(&mut self, timeout: Duration) {
+        let expired = self.last_seen.is_none_or(|last| last.elapsed() > timeout);
+        if self.online && expired {
+            self.online = false;
+            self.events.push(DeviceEventKind::Offline);
+            self.specific.went_offline();
+        }
+    }
+}
+
+pub const DISCOVERY_ID: u32 = 0x000;
+
+pub fn discovery_frame() -> CanFrame {
+    CanFrame {
+        id: DISCOVERY_ID,
+        data: [MGMT_ANNOUNCE, 0, 0, 0, 0, 0, 0, 0],
+    }
+}
+
+/// Type code announced by a node, in answer to the discovery frame
+pub fn parse_announce(frame: &CanFrame) -> Option<u8> {
+    (frame.id != DISCOVERY_ID && frame.data[0] == MGMT_ANNOUNCE | MGMT_ACK).then_some(frame.data[1])
+}
+
+/// Whether the opcode is a management command or its acknowledgement
+fn is_management(opcode: u8) -> bool {
+    (MGMT_RESET..=MGMT_ANNOUNCE).contains(&(opcode & !MGMT_ACK))
 }
 
+/// Management actions, common to every device type
+#[derive(Debug, Clone, Copy)]
 pub enum DeviceAction {
     Reset,
+    Identify(u8), // blink the LED for the given seconds
+    Ping,
+    ReadVersion,
 }
 
-impl DeviceActionTrait for DeviceAction {}
+impl DeviceActionTrait for DeviceAction {
+    fn specs() -> Vec<ActionSpec> {
+        vec![
+            ActionSpec::new("reset", "Reboot the node"),
+            ActionSpec::new("identify", "Blink the node LED")
+                .optional("duration", ArgKind::Integer { min: 1, max: 255 }),
+            ActionSpec::new("ping", "Check that the node answers"),
+            ActionSpec::new("read_version", "Read the node firmware version"),
+        ]
+    }
+
+    fn parse(name: &str, args: ActionArgs) -> Result<DeviceAction, DeviceError> {
+        match name {
+            "reset" => Ok(DeviceAction::Reset),
+            "identify" => Ok(DeviceAction::Identify(
+                args.get_opt("duration")?.unwrap_or(IDENTIFY_DEFAULT_SECS),
+            )),
+            "ping" => Ok(DeviceAction::Ping),
+            "read_version" => Ok(DeviceAction::ReadVersion),
+            _ => Err(DeviceError::Unsupported),
+        }
+    }
 
-// #[async_trait]
-// impl<D> DeviceControllableTrait for Device<D>
-// where
-//     D: DeviceTrait + DeviceControllableTrait<Action = DeviceAction>,
-// {
-//     type Action = DeviceAction;
+    fn describe(&self) -> (&'static str, Value) {
+        match self {
+            DeviceAction::Reset => ("reset", Value::Null),
+            DeviceAction::Identify(secs) => ("identify", json!({ "duration": secs })),
+            DeviceAction::Ping => ("ping", Value::Null),
+            DeviceAction::ReadVersion => ("read_version", Value::Null),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum DeviceActionResult {
+    Done,
+    Pong { latency_ms: u64 },
+    Version { version: String },
+}
+
+impl<D> Device<D>
+where
+    D: DeviceTrait,
+{
+    /// Run a management action, through the standard management frames
+    pub async fn handle_device_action(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        action: DeviceAction,
+    ) -> Result<DeviceActionResult, DeviceError> {
+        let (data, cmd) = match action {
+            DeviceAction::Reset => ([MGMT_RESET, 0, 0, 0, 0, 0, 0, 0], MGMT_RESET),
+            DeviceAction::Identify(secs) => {
+                ([MGMT_IDENTIFY, secs, 0, 0, 0, 0, 0, 0], MGMT_IDENTIFY)
+            }
+            DeviceAction::Ping => ([MGMT_PING, 0, 0, 0, 0, 0, 0, 0], MGMT_PING),
+            DeviceAction::ReadVersion => ([MGMT_VERSION, 0, 0, 0, 0, 0, 0, 0], MGMT_VERSION),
+        };
 
-//     async fn handle_action(
-//         &mut self,
-//         api: &dyn ControllerAPI,
-//         action: &DeviceAction,
-//     ) -> Result<(), DeviceError> {
-//         match action {
-//             DeviceAction::Reset => {
-//                 let _ = api.query(1, Some(0)).await;
-//             }
-//         };
+        let sent_at = Instant::now();
+        let is_ack = |frame: &CanFrame| frame.data[0] == cmd | MGMT_ACK;
+        let reply = api.request(data, &is_ack, MGMT_TIMEOUT).await?;
 
-//         Ok(())
-//     }
-// }
+        match action {
+            DeviceAction::Reset | DeviceAction::Identify(_) => Ok(DeviceActionResult::Done),
+            DeviceAction::Ping => Ok(DeviceActionResult::Pong {
+                latency_ms: sent_at.elapsed().as_millis() as u64,
+            }),
+            DeviceAction::ReadVersion => {
+                let version = (reply.data[1], reply.data[2], reply.data[3]);
+                self.version = Some(version);
+                Ok(DeviceActionResult::Version {
+                    version: format!("{}.{}.{}", version.0, version.1, version.2),
+                })
+            }
+        }
+    }
+}
 
 #[async_trait]
 impl<D, A> DeviceControllableTrait for Device<D>
@@ -87,7 +319,7 @@ where
 
     async fn handle_action(
         &mut self,
-        api: &dyn ControllerAPI,
+        api: &mut dyn ControllerAPI,
         action: &Self::Action,
     ) -> Result<(), DeviceError> {
         self.specific.handle_action(api, action).await
@@ -97,13 +329,61 @@ where
 #[async_trait]
 pub trait DeviceTrait: Send + Default + Debug {
     // fn get_id(&self) -> u32;
-    async fn handle_frame(&mut self, frame: &CanFrame) -> Result<(), DeviceError>;
+    async fn handle_frame(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        frame: &CanFrame,
+    ) -> Result<(), DeviceError>;
+
+    /// Called periodically by the controller, to run timers
+    async fn tick(&mut self, _api: &mut dyn ControllerAPI) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn snapshot(&self) -> NodeSnapshot;
+
+    /// Drain the events raised since the last call
+    fn take_events(&mut self) -> Vec<DeviceEventKind> {
+        Vec::new()
+    }
+
+    /// Drain the actions the device took on its own since the last call,
+    /// e.g. from its schedule
+    fn take_scheduled(&mut self) -> Vec<ScheduledAction> {
+        Vec::new()
+    }
+
+    /// Called when the node stops answering, what it reported is stale
+    fn went_offline(&mut self) {}
+
+    /// Frames of other nodes handled by the device, e.g. separate sensors
+    fn owns_frame(&self, _id: u32) -> bool {
+        false
+    }
+
+    /// Restore the persisted state of the device `id`
+    fn load_state(&mut self, _id: u32, _state: &StateStore) {}
+
+    /// Persist the state of the device `id`, after actions and periodically
+    fn save_state(&self, _id: u32, _state: &mut StateStore) -> Result<(), StorageError> {
+        Ok(())
+    }
 }
 
-pub trait DeviceActionTrait: Sync + Send {
-    // type Action;
+pub trait DeviceActionTrait: Any + Sync + Send {
+    /// Actions accepted by name, with their arguments
+    fn specs() -> Vec<ActionSpec>
+    where
+        Self: Sized;
+
+    /// Build the action called `name`, from its arguments
+    fn parse(name: &str, args: ActionArgs) -> Result<Self, DeviceError>
+    where
+        Self: Sized;
 
-    // fn get_action(&self) -> &Self::Action;
+    /// Name and arguments of the action, as recorded in the audit log.
+    /// Secrets such as the alarm codes are left out.
+    fn describe(&self) -> (&'static str, Value);
 }
 
 #[async_trait]
@@ -112,24 +392,243 @@ pub trait DeviceControllableTrait: Send {
 
     async fn handle_action(
         &mut self,
-        api: &dyn ControllerAPI,
+        api: &mut dyn ControllerAPI,
         action: &Self::Action,
     ) -> Result<(), DeviceError>;
 }
 
-pub struct DeviceHandle {
+/// Device of any type, as driven by the controller
+#[async_trait]
+pub trait DeviceNodeTrait: Any + Send + Debug {
+    fn id(&self) -> u32;
+
+    fn snapshot(&self) -> DeviceSnapshot;
+
+    /// Whether frames from `id` are for the device, its own or not
+    fn accepts_frame(&self, id: u32) -> bool;
+
+    async fn handle_frame(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        frame: &CanFrame,
+    ) -> Result<(), DeviceError>;
+
+    async fn tick(&mut self, api: &mut dyn ControllerAPI) -> Result<(), DeviceError>;
+
+    fn check_presence(&mut self, timeout: Duration);
+
+    fn take_events(&mut self) -> Vec<DeviceEventKind>;
+
+    fn take_scheduled(&mut self) -> Vec<ScheduledAction>;
+
+    /// Actions accepted by `parse_action`, specific and management ones
+    fn actions(&self) -> Vec<ActionSpec>;
+
+    fn parse_action(&self, name: &str, args: Value) -> Result<DeviceNodeAction, DeviceError>;
+
+    /// Run an action, `Unsupported` if it is not meant for this device type
+    async fn handle_action(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        action: &DeviceNodeAction,
+    ) -> Result<(), DeviceError>;
+
+    async fn handle_device_action(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        action: DeviceAction,
+    ) -> Result<DeviceActionResult, DeviceError>;
+
+    fn load_state(&mut self, state: &StateStore);
+
+    fn save_state(&self, state: &mut StateStore) -> Result<(), StorageError>;
+}
+
+#[async_trait]
+impl<D> DeviceNodeTrait for Device<D>
+where
+    D: DeviceTrait + DeviceControllableTrait + 'static,
+{
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn snapshot(&self) -> DeviceSnapshot {
+        Device::snapshot(self)
+    }
+
+    fn accepts_frame(&self, id: u32) -> bool {
+        id == self.id || self.specific.owns_frame(id)
+    }
+
+    async fn handle_frame(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        frame: &CanFrame,
+    ) -> Result<(), DeviceError> {
+        if frame.id == self.id {
+            DeviceTrait::handle_frame(self, api, frame).await
+        } else {
+            // the device itself was not seen
+            self.specific.handle_frame(api, frame).await
+        }
+    }
+
+    async fn tick(&mut self, api: &mut dyn ControllerAPI) -> Result<(), DeviceError> {
+        DeviceTrait::tick(self, api).await
+    }
+
+    fn check_presence(&mut self, timeout: Duration) {
+        Device::check_presence(self, timeout)
+    }
+
+    fn take_events(&mut self) -> Vec<DeviceEventKind> {
+        DeviceTrait::take_events(self)
+    }
+
+    fn take_scheduled(&mut self) -> Vec<ScheduledAction> {
+        self.specific.take_scheduled()
+    }
+
+    fn actions(&self) -> Vec<ActionSpec> {
+        let mut actions = D::Action::specs();
+        actions.extend(DeviceAction::specs());
+        actions
+    }
+
+    fn parse_action(&self, name: &str, args: Value) -> Result<DeviceNodeAction, DeviceError> {
+        let args = ActionArgs::new(args)?;
+        if D::Action::specs().iter().any(|spec| spec.name == name) {
+            D::Action::parse(name, args).map(DeviceNodeAction::new)
+        } else {
+            DeviceAction::parse(name, args).map(DeviceNodeAction::new)
+        }
+    }
+
+    async fn handle_action(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        action: &DeviceNodeAction,
+    ) -> Result<(), DeviceError> {
+        if let Some(action) = action.downcast_ref::<D::Action>() {
+            self.specific.handle_action(api, action).await
+        } else if let Some(action) = action.downcast_ref::<DeviceAction>() {
+            Device::handle_device_action(self, api, *action)
+                .await
+                .map(|_| ())
+        } else {
+            Err(DeviceError::Unsupported)
+        }
+    }
+
+    async fn handle_device_action(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        action: DeviceAction,
+    ) -> Result<DeviceActionResult, DeviceError> {
+        Device::handle_device_action(self, api, action).await
+    }
+
+    fn load_state(&mut self, state: &StateStore) {
+        self.specific.load_state(self.id, state)
+    }
+
+    fn save_state(&self, state: &mut StateStore) -> Result<(), StorageError> {
+        self.specific.save_state(self.id, state)
+    }
+}
+
+/// Typed client of a device, over the `ControllerHandle` channel
+pub struct DeviceHandle<D> {
     id: u32,
     ctrl: ControllerHandle,
+    device: PhantomData<fn() -> D>,
 }
 
-impl DeviceHandle {
-    pub fn from<T>(controller_handle: &ControllerHandle, device: &Device<T>) -> DeviceHandle
-    where
-        T: DeviceTrait,
-    {
+impl<D> Clone for DeviceHandle<D> {
+    fn clone(&self) -> Self {
+        DeviceHandle {
+            id: self.id,
+            ctrl: self.ctrl.clone(),
+            device: PhantomData,
+        }
+    }
+}
+
+impl<D> DeviceHandle<D>
+where
+    D: DeviceTrait + DeviceControllableTrait + 'static,
+{
+    pub fn from(controller_handle: &ControllerHandle, device: &Device<D>) -> DeviceHandle<D> {
         DeviceHandle {
             id: device.get_id(),
             ctrl: controller_handle.clone(),
+            device: PhantomData,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Run an action of the device type
+    pub async fn send(&self, action: D::Action) -> Result<(), DeviceError> {
+        self.ctrl
+            .query_device(self.id, DeviceNodeAction::new(action), Origin::Internal)
+            .await
+    }
+
+    /// Run a management action
+    pub async fn device_action(
+        &self,
+        action: DeviceAction,
+    ) -> Result<DeviceActionResult, DeviceError> {
+        self.ctrl
+            .device_action(self.id, action, Origin::Internal)
+            .await
+            .unwrap_or(Err(DeviceError::NotFound))
+    }
+
+    pub async fn snapshot(&self) -> Option<DeviceSnapshot> {
+        self.inspect(|device| device.snapshot()).await
+    }
+
+    /// Read the device with `f`, from within the controller task
+    pub async fn inspect<R, F>(&self, f: F) -> Option<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Device<D>) -> R + Send + 'static,
+    {
+        self.ctrl.inspect(self.id, f).await
+    }
+
+    /// Events raised by the device from now on
+    pub async fn subscribe(&self) -> DeviceEvents {
+        DeviceEvents {
+            id: self.id,
+            receiver: self.ctrl.subscribe().await,
+        }
+    }
+}
+
+/// Events of a single device, see `DeviceHandle::subscribe`
+pub struct DeviceEvents {
+    id: u32,
+    receiver: broadcast::Receiver<DeviceEvent>,
+}
+
+impl DeviceEvents {
+    /// Next event of the device, None once the controller is gone
+    pub async fn recv(&mut self) -> Option<DeviceEvent> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) if event.device_id == self.id => return Some(event),
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    println!("Device {} subscriber missed {} events", self.id, count);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
         }
     }
 }
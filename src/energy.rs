@@ -0,0 +1,245 @@
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::heater::{HeaterState, ZONE_COUNT};
+
+// Hourly buckets are dropped after this many days, days and months are kept
+const HOURS_RETENTION_DAYS: i64 = 31;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnergyPeriod {
+    Hour,
+    Day,
+    Month,
+}
+
+impl EnergyPeriod {
+    pub fn parse(value: &str) -> Option<EnergyPeriod> {
+        match value {
+            "hour" => Some(EnergyPeriod::Hour),
+            "day" => Some(EnergyPeriod::Day),
+            "month" => Some(EnergyPeriod::Month),
+            _ => None,
+        }
+    }
+
+    /// Bucket key of the period containing `at`, local time
+    fn key(&self, at: &DateTime<Local>) -> String {
+        let format = match self {
+            EnergyPeriod::Hour => "%Y-%m-%dT%H",
+            EnergyPeriod::Day => "%Y-%m-%d",
+            EnergyPeriod::Month => "%Y-%m",
+        };
+        at.format(format).to_string()
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ZoneEnergy {
+    pub on_secs: BTreeMap<HeaterState, f64>, // heating time, per state
+    pub kwh: f64,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct EnergyBucket {
+    pub zones: [ZoneEnergy; ZONE_COUNT],
+}
+
+impl EnergyBucket {
+    pub fn kwh(&self) -> f64 {
+        self.zones.iter().map(|zone| zone.kwh).sum()
+    }
+
+    fn add(&mut self, zone: usize, state: HeaterState, secs: f64, kwh: f64) {
+        let zone = &mut self.zones[zone];
+        *zone.on_secs.entry(state).or_default() += secs;
+        zone.kwh += kwh;
+    }
+}
+
+/// Energy of a period, as returned by the REST API
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct EnergyTotal {
+    pub period: String,
+    pub kwh: f64,
+    pub zones: [ZoneEnergy; ZONE_COUNT],
+}
+
+/// Heating energy of a heater node, aggregated per hour, day and month
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct EnergyLog {
+    pub hours: BTreeMap<String, EnergyBucket>,
+    pub days: BTreeMap<String, EnergyBucket>,
+    pub months: BTreeMap<String, EnergyBucket>,
+}
+
+impl EnergyLog {
+    /// Account `secs` seconds of heating of a zone drawing `watts`
+    pub fn add(
+        &mut self,
+        at: &DateTime<Local>,
+        zone: usize,
+        state: HeaterState,
+        secs: f64,
+        watts: f32,
+    ) {
+        let kwh = watts as f64 * secs / 3600.0 / 1000.0;
+
+        for period in [EnergyPeriod::Hour, EnergyPeriod::Day, EnergyPeriod::Month] {
+            self.buckets_mut(period)
+                .entry(period.key(at))
+                .or_default()
+                .add(zone, state, secs, kwh);
+        }
+
+        let oldest = EnergyPeriod::Hour.key(&(*at - Duration::days(HOURS_RETENTION_DAYS)));
+        self.hours = self.hours.split_off(&oldest);
+    }
+
+    pub fn buckets(&self, period: EnergyPeriod) -> &BTreeMap<String, EnergyBucket> {
+        match period {
+            EnergyPeriod::Hour => &self.hours,
+            EnergyPeriod::Day => &self.days,
+            EnergyPeriod::Month => &self.months,
+        }
+    }
+
+    pub fn totals(&self, period: EnergyPeriod) -> Vec<EnergyTotal> {
+        self.buckets(period)
+            .iter()
+            .map(|(key, bucket)| EnergyTotal {
+                period: key.clone(),
+                kwh: bucket.kwh(),
+                zones: bucket.zones.clone(),
+            })
+            .collect()
+    }
+
+    fn buckets_mut(&mut self, period: EnergyPeriod) -> &mut BTreeMap<String, EnergyBucket> {
+        match period {
+            EnergyPeriod::Hour => &mut self.hours,
+            EnergyPeriod::Day => &mut self.days,
+            EnergyPeriod::Month => &mut self.months,
+        }
+    }
+
+    /// One line per period, zone and state: `period,zone,state,on_secs,kwh`,
+    /// the energy of the zone is split between its states by heating time.
+    pub fn to_csv(&self, period: EnergyPeriod) -> String {
+        let mut csv = String::from("period,zone,state,on_secs,kwh\n");
+
+        for (key, bucket) in self.buckets(period) {
+            for (zone, energy) in bucket.zones.iter().enumerate() {
+                let total_secs: f64 = energy.on_secs.values().sum();
+                for (state, secs) in &energy.on_secs {
+                    let kwh = if total_secs > 0.0 {
+                        energy.kwh * secs / total_secs
+                    } else {
+                        0.0
+                    };
+                    let state = serde_json::to_value(state)
+                        .ok()
+                        .and_then(|v| v.as_str().map(str::to_string))
+                        .unwrap_or_default();
+                    csv.push_str(&format!(
+                        "{},{},{},{:.0},{:.3}\n",
+                        key, zone, state, secs, kwh
+                    ));
+                }
+            }
+        }
+
+        csv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn add_fills_every_period() {
+        let mut log = EnergyLog::default();
+        log.add(
+            &at(2024, 1, 15, 10, 30),
+            0,
+            HeaterState::Comfort,
+            3600.0,
+            1000.0,
+        );
+
+        for (period, key) in [
+            (EnergyPeriod::Hour, "2024-01-15T10"),
+            (EnergyPeriod::Day, "2024-01-15"),
+            (EnergyPeriod::Month, "2024-01"),
+        ] {
+            let bucket = &log.buckets(period)[key];
+            assert!((bucket.kwh() - 1.0).abs() < 1e-9);
+            assert_eq!(bucket.zones[0].on_secs[&HeaterState::Comfort], 3600.0);
+            assert_eq!(bucket.zones[1].kwh, 0.0);
+        }
+    }
+
+    #[test]
+    fn add_splits_buckets_at_boundaries() {
+        let mut log = EnergyLog::default();
+        log.add(
+            &at(2024, 1, 31, 23, 30),
+            0,
+            HeaterState::Eco,
+            1800.0,
+            2000.0,
+        );
+        log.add(&at(2024, 2, 1, 0, 30), 0, HeaterState::Eco, 1800.0, 2000.0);
+        log.add(
+            &at(2024, 2, 1, 0, 45),
+            1,
+            HeaterState::Comfort,
+            900.0,
+            2000.0,
+        );
+
+        assert_eq!(log.hours.len(), 2);
+        assert_eq!(log.days.len(), 2);
+        assert_eq!(log.months.len(), 2);
+        assert!((log.hours["2024-02-01T00"].kwh() - 1.5).abs() < 1e-9);
+        assert!((log.days["2024-01-31"].kwh() - 1.0).abs() < 1e-9);
+        assert!((log.months["2024-02"].kwh() - 1.5).abs() < 1e-9);
+        assert_eq!(
+            log.months["2024-02"].zones[1].on_secs[&HeaterState::Comfort],
+            900.0
+        );
+    }
+
+    #[test]
+    fn hours_are_dropped_after_retention() {
+        let mut log = EnergyLog::default();
+        log.add(
+            &at(2024, 1, 1, 12, 0),
+            0,
+            HeaterState::Comfort,
+            60.0,
+            1000.0,
+        );
+        log.add(
+            &at(2024, 3, 1, 12, 0),
+            0,
+            HeaterState::Comfort,
+            60.0,
+            1000.0,
+        );
+
+        assert_eq!(log.hours.keys().collect::<Vec<_>>(), ["2024-03-01T12"]);
+        assert_eq!(log.days.len(), 2);
+        assert_eq!(log.months.len(), 2);
+    }
+}
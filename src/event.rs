@@ -0,0 +1,107 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    alarm::AlarmState,
+    heater::{HeaterState, WindowCause},
+};
+
+/// Something that happened on a device, as seen by the controller.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DeviceEventKind {
+    Online,
+    Offline,
+    Frame {
+        data: [u8; 8],
+    },
+    AlarmTriggered {
+        zones: u8,
+    },
+    AlarmStateChanged {
+        state: AlarmState,
+    },
+    AlarmCodeRejected {
+        attempts: u32,
+    },
+    AlarmLockedOut,
+    AlarmDuress,
+    AlarmLightsChanged {
+        front: bool,
+        rear: bool,
+    },
+    // recorded as heater_mode_changed before the zones were reported
+    #[serde(alias = "heater_mode_changed")]
+    HeaterActiveChanged {
+        active: bool,
+    },
+    HeaterZoneChanged {
+        zone: usize,
+        state: HeaterState,
+    },
+    HeaterWindowOpened {
+        zone: usize,
+        cause: WindowCause,
+    },
+    HeaterWindowClosed {
+        zone: usize,
+    },
+}
+
+impl DeviceEventKind {
+    /// Name of the event kind, as used in the serialized form and in queries
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceEventKind::Online => "online",
+            DeviceEventKind::Offline => "offline",
+            DeviceEventKind::Frame { .. } => "frame",
+            DeviceEventKind::AlarmTriggered { .. } => "alarm_triggered",
+            DeviceEventKind::AlarmStateChanged { .. } => "alarm_state_changed",
+            DeviceEventKind::AlarmCodeRejected { .. } => "alarm_code_rejected",
+            DeviceEventKind::AlarmLockedOut => "alarm_locked_out",
+            DeviceEventKind::AlarmDuress => "alarm_duress",
+            DeviceEventKind::AlarmLightsChanged { .. } => "alarm_lights_changed",
+            DeviceEventKind::HeaterActiveChanged { .. } => "heater_active_changed",
+            DeviceEventKind::HeaterZoneChanged { .. } => "heater_zone_changed",
+            DeviceEventKind::HeaterWindowOpened { .. } => "heater_window_opened",
+            DeviceEventKind::HeaterWindowClosed { .. } => "heater_window_closed",
+        }
+    }
+}
+
+/// Timestamped event, as recorded in the history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DeviceEvent {
+    pub timestamp: DateTime<Utc>,
+    pub device_id: u32,
+
+    #[serde(flatten)]
+    pub kind: DeviceEventKind,
+}
+
+impl DeviceEvent {
+    pub fn new(device_id: u32, kind: DeviceEventKind) -> DeviceEvent {
+        DeviceEvent {
+            timestamp: Utc::now(),
+            device_id,
+            kind,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn former_heater_mode_events_load() {
+        let line = r#"{"timestamp":"2023-10-01T00:00:00Z","device_id":2,"kind":"heater_mode_changed","active":true}"#;
+        let event: DeviceEvent = serde_json::from_str(line).unwrap();
+        assert_eq!(
+            event.kind,
+            DeviceEventKind::HeaterActiveChanged { active: true }
+        );
+    }
+}
@@ -1,34 +1,704 @@
+use async_trait::async_trait;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
 use crate::{
+    action::{ActionArgs, ActionSpec, ArgKind},
     can::CanFrame,
     controller::ControllerAPI,
-    device::{DeviceActionTrait, DeviceControllableTrait, DeviceError, DeviceTrait},
+    device::{
+        DeviceActionTrait, DeviceControllableTrait, DeviceError, DeviceTrait, NodeSnapshot,
+        ScheduledAction,
+    },
+    energy::EnergyLog,
+    event::DeviceEventKind,
+    registry::DevicePlugin,
+    schedule::{HeaterSchedule, ScheduleOverride},
+    storage::{StateStore, StorageError},
 };
 
-#[derive(Debug, Default)]
-pub struct HeaterNode {
-    pub active: bool,
+// Status frame sent by the heater node, periodically and in response to
+// commands: [FRAME_STATUS, left state, right state, outputs, 0, 0, 0, 0]
+const FRAME_STATUS: u8 = 0x01;
+
+// Temperature frame, sent by the heater node or by separate sensor nodes:
+// [FRAME_TEMPERATURE, zone, temperature (i16, big endian, 0.1 °C), 0, 0, 0, 0]
+// sensor nodes only measure a single zone, their zone byte is ignored.
+const FRAME_TEMPERATURE: u8 = 0x02;
+
+// Window contact frame, same origin as the temperature frame:
+// [FRAME_WINDOW, zone, open (0/1), 0, 0, 0, 0, 0]
+const FRAME_WINDOW: u8 = 0x03;
+
+// Zones command: [CMD_SET_ZONES, left state, right state, 0, 0, 0, 0, 0]
+const CMD_SET_ZONES: u8 = 0x10;
+// Outputs command: [CMD_SET_OUTPUTS, outputs, 0, 0, 0, 0, 0, 0]
+// (bit 0: left, bit 1: right), both commands are answered with a status frame
+const CMD_SET_OUTPUTS: u8 = 0x11;
+const ACK_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Number of zones of a heater node (left and right)
+pub const ZONE_COUNT: usize = 2;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Setpoints {
+    pub comfort: f32, // in °C
+    pub eco: f32,
+    pub anti_freeze: f32,
 }
 
-#[async_trait]
-impl DeviceTrait for HeaterNode {
-    async fn handle_frame(&mut self, frame: &CanFrame) -> Result<(), DeviceError> {
-        Ok(())
+impl Default for Setpoints {
+    fn default() -> Setpoints {
+        Setpoints {
+            comfort: 20.0,
+            eco: 17.0,
+            anti_freeze: 7.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WindowConfig {
+    pub detection: bool,       // detect open windows from the temperature
+    pub temperature_drop: f32, // in °C, within `drop_period`
+    pub drop_period: u32,      // in seconds
+    pub duration: u32,         // in seconds, AntiFreeze time after a temperature drop
+}
+
+impl Default for WindowConfig {
+    fn default() -> WindowConfig {
+        WindowConfig {
+            detection: true,
+            temperature_drop: 1.0,
+            drop_period: 300,
+            duration: 1800,
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum WindowCause {
+    Temperature,
+    Contact,
+}
+
+#[derive(Debug, Clone)]
+struct OpenWindow {
+    until: Option<Instant>, // None: until the contact reports it closed
+}
+
+/// Separate temperature sensor node, measuring a zone
+#[derive(Debug, Clone, Deserialize)]
+pub struct ZoneSensor {
+    pub zone: usize,
+    pub id: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HeaterConfig {
+    pub thermostat: bool, // drive the outputs from the measured temperature
+    pub setpoints: Setpoints,
+    pub hysteresis: f32,          // in °C, around the setpoint
+    pub min_on_time: u32,         // in seconds, to protect the relays
+    pub min_off_time: u32,        // in seconds
+    pub sensors: Vec<ZoneSensor>, // zones not measured by the heater node itself
+    pub window: WindowConfig,
+    pub power: [f32; ZONE_COUNT], // in W, drawn by each zone while heating
+}
+
+impl Default for HeaterConfig {
+    fn default() -> HeaterConfig {
+        HeaterConfig {
+            thermostat: true,
+            setpoints: Setpoints::default(),
+            hysteresis: 0.5,
+            min_on_time: 120,
+            min_off_time: 120,
+            sensors: Vec::new(),
+            window: WindowConfig::default(),
+            power: [1000.0; ZONE_COUNT],
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
 pub enum HeaterState {
+    #[default]
     Off,
     Comfort,
     Eco,
     AntiFreeze,
 }
 
+impl HeaterState {
+    fn encode(&self) -> u8 {
+        match self {
+            HeaterState::Off => 0,
+            HeaterState::Comfort => 1,
+            HeaterState::Eco => 2,
+            HeaterState::AntiFreeze => 3,
+        }
+    }
+
+    pub fn setpoint(&self, setpoints: &Setpoints) -> Option<f32> {
+        match self {
+            HeaterState::Off => None,
+            HeaterState::Comfort => Some(setpoints.comfort),
+            HeaterState::Eco => Some(setpoints.eco),
+            HeaterState::AntiFreeze => Some(setpoints.anti_freeze),
+        }
+    }
+
+    fn decode(code: u8) -> Option<HeaterState> {
+        match code {
+            0 => Some(HeaterState::Off),
+            1 => Some(HeaterState::Comfort),
+            2 => Some(HeaterState::Eco),
+            3 => Some(HeaterState::AntiFreeze),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct HeaterZone {
+    pub state: HeaterState,     // as reported by the node
+    pub requested: HeaterState, // applied when the heater is active
+    pub temperature: Option<f32>,
+    pub setpoint: Option<f32>,
+    pub output: bool, // heating, as reported by the node
+    pub window_open: bool,
+    // requested state while the window is open, restored once closed
+    #[serde(default)]
+    pub saved_request: Option<HeaterState>,
+
+    #[serde(skip)]
+    output_since: Option<Instant>,
+    #[serde(skip)]
+    window: Option<OpenWindow>,
+    #[serde(skip)]
+    #[cfg_attr(feature = "openapi", schema(ignore, value_type = Object))]
+    samples: VecDeque<(Instant, f32)>, // temperatures over the last drop period
+}
+
+impl HeaterZone {
+    /// State asked for the zone, the saved one while its window is open
+    pub fn wanted(&self) -> HeaterState {
+        self.saved_request.unwrap_or(self.requested)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct HeaterSnapshot {
+    pub active: bool,
+    pub zones: [HeaterZone; ZONE_COUNT],
+}
+
+#[derive(Debug, Default)]
+pub struct HeaterNode {
+    pub config: HeaterConfig,
+    pub active: bool,
+    pub zones: [HeaterZone; ZONE_COUNT],
+    pub schedule: Option<HeaterSchedule>,
+    pub energy: EnergyLog,
+
+    // heating time is accounted up to this instant
+    accounted_at: Option<Instant>,
+    // last states applied by the schedule, manual changes are kept until the
+    // schedule changes its mind
+    scheduled: Option<[HeaterState; ZONE_COUNT]>,
+    events: Vec<DeviceEventKind>,
+    // states the schedule failed to apply, retried but audited once
+    schedule_failed: Option<[HeaterState; ZONE_COUNT]>,
+    // changes applied by the schedule, to be audited
+    scheduled_actions: Vec<ScheduledAction>,
+}
+
+impl HeaterNode {
+    pub fn new(config: HeaterConfig) -> HeaterNode {
+        HeaterNode {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// States to command, all zones are off while the heater is inactive
+    fn target(&self) -> [HeaterState; ZONE_COUNT] {
+        if self.active {
+            self.zones.clone().map(|zone| zone.requested)
+        } else {
+            [HeaterState::Off; ZONE_COUNT]
+        }
+    }
+
+    fn schedule_mut(&mut self) -> Result<&mut HeaterSchedule, DeviceError> {
+        self.scheduled = None;
+        self.schedule.as_mut().ok_or(DeviceError::InvalidState)
+    }
+
+    async fn apply(&mut self, api: &mut dyn ControllerAPI) -> Result<(), DeviceError> {
+        let [left, right] = self.target();
+        let is_ack = |frame: &CanFrame| frame.data[0] == FRAME_STATUS;
+        let status = api
+            .request(
+                [CMD_SET_ZONES, left.encode(), right.encode(), 0, 0, 0, 0, 0],
+                &is_ack,
+                ACK_TIMEOUT,
+            )
+            .await?;
+
+        self.update_status(&status)
+    }
+
+    /// Account the heating time since the last call to the current zone
+    /// states, before they change
+    fn account_energy(&mut self) {
+        let now = Instant::now();
+        if let Some(last) = self.accounted_at {
+            let secs = now.duration_since(last).as_secs_f64();
+            let at = Local::now();
+            for (zone, zone_state) in self.zones.iter().enumerate() {
+                if zone_state.output {
+                    let watts = self.config.power[zone];
+                    self.energy.add(&at, zone, zone_state.state, secs, watts);
+                }
+            }
+        }
+        self.accounted_at = Some(now);
+    }
+
+    /// Track the zone states reported by the node
+    fn update_status(&mut self, frame: &CanFrame) -> Result<(), DeviceError> {
+        self.account_energy();
+
+        let mut states = [HeaterState::Off; ZONE_COUNT];
+        for (zone, state) in states.iter_mut().enumerate() {
+            *state = HeaterState::decode(frame.data[1 + zone]).ok_or(DeviceError::InvalidFrame)?;
+        }
+
+        for (zone, state) in states.into_iter().enumerate() {
+            let zone_state = &mut self.zones[zone];
+            if zone_state.state != state {
+                zone_state.state = state;
+                zone_state.setpoint = state.setpoint(&self.config.setpoints);
+                self.events
+                    .push(DeviceEventKind::HeaterZoneChanged { zone, state });
+            }
+
+            let output = frame.data[3] & (1 << zone) != 0;
+            if zone_state.output != output || zone_state.output_since.is_none() {
+                zone_state.output = output;
+                zone_state.output_since = Some(Instant::now());
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_temperature(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        zone: usize,
+        frame: &CanFrame,
+    ) -> Result<(), DeviceError> {
+        let temperature = i16::from_be_bytes([frame.data[2], frame.data[3]]) as f32 / 10.0;
+        self.zones[zone].temperature = Some(temperature);
+
+        let config = &self.config.window;
+        if !config.detection {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let period = Duration::from_secs(config.drop_period as u64);
+        let samples = &mut self.zones[zone].samples;
+        samples.retain(|(at, _)| now.duration_since(*at) <= period);
+        samples.push_back((now, temperature));
+
+        let max = samples.iter().map(|(_, t)| *t).fold(f32::MIN, f32::max);
+        if max - temperature >= config.temperature_drop {
+            samples.clear();
+            let until = now + Duration::from_secs(config.duration as u64);
+            self.open_window(api, zone, WindowCause::Temperature, Some(until))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Put the zone in AntiFreeze while the window is open
+    async fn open_window(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        zone: usize,
+        cause: WindowCause,
+        until: Option<Instant>,
+    ) -> Result<(), DeviceError> {
+        let zone_state = &mut self.zones[zone];
+        if let Some(window) = &mut zone_state.window {
+            // the contact keeps it open until closed
+            if window.until.is_some() {
+                window.until = until;
+            }
+            return Ok(());
+        }
+
+        zone_state.window = Some(OpenWindow { until });
+        zone_state.window_open = true;
+        zone_state.saved_request = Some(zone_state.requested);
+        zone_state.requested = HeaterState::AntiFreeze;
+        self.events
+            .push(DeviceEventKind::HeaterWindowOpened { zone, cause });
+
+        self.apply(api).await
+    }
+
+    /// Restore the state the zone was in before the window was opened
+    async fn close_window(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        zone: usize,
+    ) -> Result<(), DeviceError> {
+        let zone_state = &mut self.zones[zone];
+        if zone_state.window.take().is_none() {
+            return Ok(());
+        }
+
+        zone_state.window_open = false;
+        if let Some(saved) = zone_state.saved_request.take() {
+            zone_state.requested = saved;
+        }
+        self.events
+            .push(DeviceEventKind::HeaterWindowClosed { zone });
+
+        self.apply(api).await
+    }
+
+    /// Request a state for the zone, kept for when its window is closed if
+    /// open, the zone staying in AntiFreeze meanwhile
+    fn request(&mut self, zone: usize, state: HeaterState) {
+        let zone_state = &mut self.zones[zone];
+        match &mut zone_state.saved_request {
+            Some(saved) => *saved = state,
+            None => zone_state.requested = state,
+        }
+    }
+
+    async fn close_expired_windows(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+    ) -> Result<(), DeviceError> {
+        let now = Instant::now();
+        for zone in 0..ZONE_COUNT {
+            let expired = self.zones[zone]
+                .window
+                .as_ref()
+                .is_some_and(|window| window.until.is_some_and(|until| now >= until));
+            if expired {
+                self.close_window(api, zone).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn apply_schedule(&mut self, api: &mut dyn ControllerAPI) -> Result<(), DeviceError> {
+        let Some(schedule) = &self.schedule else {
+            return Ok(());
+        };
+
+        let states = schedule.states_at(&Local::now());
+        if self.scheduled == Some(states) {
+            return Ok(());
+        }
+
+        for (zone, state) in states.into_iter().enumerate() {
+            self.request(zone, state);
+        }
+        let [left, right] = states;
+        let action = HeaterAction::HeaterPower(left, right).describe();
+        match self.apply(api).await {
+            Ok(()) => {
+                self.scheduled = Some(states);
+                self.schedule_failed = None;
+                self.scheduled_actions.push((action, Ok(())));
+                Ok(())
+            }
+            Err(err) => {
+                if self.schedule_failed != Some(states) {
+                    self.schedule_failed = Some(states);
+                    self.scheduled_actions.push((action, Err(err.clone())));
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Zone measured by a separate sensor node
+    pub fn sensor_zone(&self, id: u32) -> Option<usize> {
+        self.config
+            .sensors
+            .iter()
+            .find(|sensor| sensor.id == id)
+            .map(|sensor| sensor.zone)
+            .filter(|zone| *zone < ZONE_COUNT)
+    }
+
+    /// Output wanted for the zone, according to the hysteresis and the
+    /// minimum on/off times, or `None` to keep the current one.
+    fn thermostat(&self, zone: &HeaterZone) -> Option<bool> {
+        let Some(setpoint) = zone.setpoint else {
+            return Some(false);
+        };
+        let temperature = zone.temperature?;
+
+        let half = self.config.hysteresis / 2.0;
+        let (wanted, min_time) = if zone.output && temperature >= setpoint + half {
+            (false, self.config.min_on_time)
+        } else if !zone.output && temperature <= setpoint - half {
+            (true, self.config.min_off_time)
+        } else {
+            return None;
+        };
+
+        let elapsed = zone
+            .output_since
+            .map_or(Duration::MAX, |since| since.elapsed());
+        (elapsed >= Duration::from_secs(min_time as u64)).then_some(wanted)
+    }
+
+    async fn regulate(&mut self, api: &mut dyn ControllerAPI) -> Result<(), DeviceError> {
+        if !self.config.thermostat {
+            return Ok(());
+        }
+
+        let mut outputs = 0;
+        let mut changed = false;
+        for (zone, zone_state) in self.zones.iter().enumerate() {
+            let output = match self.thermostat(zone_state) {
+                Some(output) => {
+                    changed |= output != zone_state.output;
+                    output
+                }
+                None => zone_state.output,
+            };
+            if output {
+                outputs |= 1 << zone;
+            }
+        }
+        if !changed {
+            return Ok(());
+        }
+
+        let is_ack = |frame: &CanFrame| frame.data[0] == FRAME_STATUS;
+        let status = api
+            .request(
+                [CMD_SET_OUTPUTS, outputs, 0, 0, 0, 0, 0, 0],
+                &is_ack,
+                ACK_TIMEOUT,
+            )
+            .await?;
+
+        self.update_status(&status)
+    }
+}
+
+#[async_trait]
+impl DeviceTrait for HeaterNode {
+    async fn handle_frame(
+        &mut self,
+        api: &mut dyn ControllerAPI,
+        frame: &CanFrame,
+    ) -> Result<(), DeviceError> {
+        // frames from a sensor node concern its zone, whatever the zone byte
+        let sensor_zone = self.sensor_zone(frame.id);
+
+        match frame.data[0] {
+            FRAME_STATUS if sensor_zone.is_none() => self.update_status(frame),
+            FRAME_TEMPERATURE | FRAME_WINDOW => {
+                let zone = match sensor_zone {
+                    Some(zone) => zone,
+                    None => {
+                        let zone = frame.data[1] as usize;
+                        if zone >= ZONE_COUNT {
+                            return Err(DeviceError::InvalidFrame);
+                        }
+                        // zones measured by a sensor node ignore the heater node
+                        if self.config.sensors.iter().any(|s| s.zone == zone) {
+                            return Ok(());
+                        }
+                        zone
+                    }
+                };
+
+                if frame.data[0] == FRAME_TEMPERATURE {
+                    self.handle_temperature(api, zone, frame).await?;
+                } else if frame.data[2] != 0 {
+                    self.open_window(api, zone, WindowCause::Contact, None)
+                        .await?;
+                } else {
+                    self.close_window(api, zone).await?;
+                }
+                self.regulate(api).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn tick(&mut self, api: &mut dyn ControllerAPI) -> Result<(), DeviceError> {
+        self.account_energy();
+        self.close_expired_windows(api).await?;
+        self.apply_schedule(api).await?;
+        self.regulate(api).await
+    }
+
+    fn snapshot(&self) -> NodeSnapshot {
+        NodeSnapshot::Heater(HeaterSnapshot {
+            active: self.active,
+            zones: self.zones.clone(),
+        })
+    }
+
+    fn take_scheduled(&mut self) -> Vec<ScheduledAction> {
+        std::mem::take(&mut self.scheduled_actions)
+    }
+
+    fn went_offline(&mut self) {
+        // the outputs are unknown until the next status frame, nothing is
+        // accounted in between
+        self.account_energy();
+        for zone in &mut self.zones {
+            zone.output = false;
+            zone.output_since = None;
+        }
+    }
+
+    fn take_events(&mut self) -> Vec<DeviceEventKind> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn owns_frame(&self, id: u32) -> bool {
+        self.sensor_zone(id).is_some()
+    }
+
+    fn load_state(&mut self, id: u32, state: &StateStore) {
+        self.schedule = state.get(&schedule_key(id));
+        self.energy = state.get(&energy_key(id)).unwrap_or_default();
+    }
+
+    fn save_state(&self, id: u32, state: &mut StateStore) -> Result<(), StorageError> {
+        match &self.schedule {
+            Some(schedule) => state.set(&schedule_key(id), schedule)?,
+            None => state.remove(&schedule_key(id))?,
+        }
+        state.set(&energy_key(id), &self.energy)
+    }
+}
+
+fn schedule_key(id: u32) -> String {
+    format!("heater/{}/schedule", id)
+}
+
+fn energy_key(id: u32) -> String {
+    format!("heater/{}/energy", id)
+}
+
 pub enum HeaterAction {
     SetActive(bool),
     HeaterPower(HeaterState, HeaterState), // left and right heater power
+    SetSchedule(Option<HeaterSchedule>),
+    Override(usize, Option<ScheduleOverride>), // zone, None to clear
+    Away(Option<ScheduleOverride>),            // None to clear
+}
+
+impl DeviceActionTrait for HeaterAction {
+    fn specs() -> Vec<ActionSpec> {
+        let state = ArgKind::Enum {
+            values: &["off", "comfort", "eco", "anti_freeze"],
+        };
+        vec![
+            ActionSpec::new("set_active", "Switch the heater on or off")
+                .arg("active", ArgKind::Bool),
+            ActionSpec::new("heater_power", "Set the state of both zones")
+                .arg("left", state.clone())
+                .arg("right", state),
+            ActionSpec::new(
+                "set_schedule",
+                "Replace the weekly program, none to remove it",
+            )
+            .optional("schedule", ArgKind::Json),
+            ActionSpec::new("override", "Force the state of a zone, none to clear")
+                .arg(
+                    "zone",
+                    ArgKind::Integer {
+                        min: 0,
+                        max: ZONE_COUNT as i64 - 1,
+                    },
+                )
+                .optional("override", ArgKind::Json),
+            ActionSpec::new("away", "Force the state of all zones, none to clear")
+                .optional("away", ArgKind::Json),
+        ]
+    }
+
+    fn parse(name: &str, args: ActionArgs) -> Result<HeaterAction, DeviceError> {
+        match name {
+            "set_active" => Ok(HeaterAction::SetActive(args.get("active")?)),
+            "heater_power" => Ok(HeaterAction::HeaterPower(
+                args.get("left")?,
+                args.get("right")?,
+            )),
+            "set_schedule" => Ok(HeaterAction::SetSchedule(args.get_opt("schedule")?)),
+            "override" => Ok(HeaterAction::Override(
+                args.get("zone")?,
+                args.get_opt("override")?,
+            )),
+            "away" => Ok(HeaterAction::Away(args.get_opt("away")?)),
+            _ => Err(DeviceError::Unsupported),
+        }
+    }
+
+    fn describe(&self) -> (&'static str, Value) {
+        match self {
+            HeaterAction::SetActive(active) => ("set_active", json!({ "active": active })),
+            HeaterAction::HeaterPower(left, right) => {
+                ("heater_power", json!({ "left": left, "right": right }))
+            }
+            HeaterAction::SetSchedule(schedule) => {
+                ("set_schedule", json!({ "schedule": schedule }))
+            }
+            HeaterAction::Override(zone, over) => {
+                ("override", json!({ "zone": zone, "override": over }))
+            }
+            HeaterAction::Away(away) => ("away", json!({ "away": away })),
+        }
+    }
 }
 
-impl DeviceActionTrait for HeaterAction {}
+impl DevicePlugin for HeaterNode {
+    const TYPE_CODE: u8 = 0x02;
+    const TYPE_NAME: &'static str = "heater";
+
+    type Settings = HeaterConfig;
+
+    fn create(settings: HeaterConfig) -> HeaterNode {
+        HeaterNode::new(settings)
+    }
+}
 
 #[async_trait]
 impl DeviceControllableTrait for HeaterNode {
@@ -36,18 +706,432 @@ impl DeviceControllableTrait for HeaterNode {
 
     async fn handle_action(
         &mut self,
-        api: &dyn ControllerAPI,
+        api: &mut dyn ControllerAPI,
         action: &HeaterAction,
     ) -> Result<(), DeviceError> {
         match action {
             HeaterAction::SetActive(active) => {
+                let previous = self.active;
                 self.active = *active;
+                if let Err(err) = self.apply(api).await {
+                    // not switched, the node did not acknowledge it
+                    self.active = previous;
+                    return Err(err);
+                }
+                if previous != *active {
+                    self.events
+                        .push(DeviceEventKind::HeaterActiveChanged { active: *active });
+                }
+                return Ok(());
             }
             HeaterAction::HeaterPower(left, right) => {
-                let z = api.query_frame(1, Some(0)).await;
+                self.request(0, *left);
+                self.request(1, *right);
+            }
+            // schedule changes are applied by the next tick
+            HeaterAction::SetSchedule(schedule) => {
+                self.schedule = schedule.clone();
+                self.scheduled = None;
+                return Ok(());
+            }
+            HeaterAction::Override(zone, override_) => {
+                let program = self
+                    .schedule_mut()?
+                    .zones
+                    .get_mut(*zone)
+                    .ok_or(DeviceError::Unsupported)?;
+                program.override_ = override_.clone();
+                return Ok(());
+            }
+            HeaterAction::Away(away) => {
+                self.schedule_mut()?.away = away.clone();
+                return Ok(());
             }
         };
 
-        Ok(())
+        self.apply(api).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODE_ID: u32 = 2;
+    const SENSOR_ID: u32 = 11;
+
+    /// Heater node answering the commands with its status, recording them
+    #[derive(Default)]
+    struct TestBus {
+        sent: Vec<[u8; 8]>,
+        status: [u8; 8],
+        offline: bool, // the requests time out
+    }
+
+    #[async_trait]
+    impl ControllerAPI for TestBus {
+        async fn command(&mut self, data: [u8; 8]) {
+            self.sent.push(data);
+        }
+
+        async fn request(
+            &mut self,
+            data: [u8; 8],
+            _is_ack: &(dyn for<'f> Fn(&'f CanFrame) -> bool + Sync),
+            _timeout: Duration,
+        ) -> Result<CanFrame, DeviceError> {
+            self.sent.push(data);
+            if self.offline {
+                return Err(DeviceError::Timeout);
+            }
+            self.status[0] = FRAME_STATUS;
+            match data[0] {
+                CMD_SET_ZONES => self.status[1..3].copy_from_slice(&data[1..3]),
+                CMD_SET_OUTPUTS => self.status[3] = data[1],
+                _ => {}
+            }
+            Ok(CanFrame {
+                id: NODE_ID,
+                data: self.status,
+            })
+        }
+
+        async fn query_frame(&mut self, _id: u32, _timeout_ms: Option<u32>) -> u32 {
+            0
+        }
+    }
+
+    fn frame(id: u32, data: [u8; 8]) -> CanFrame {
+        CanFrame { id, data }
+    }
+
+    fn temperature_frame(id: u32, zone: u8, temperature: f32) -> CanFrame {
+        let [high, low] = ((temperature * 10.0).round() as i16).to_be_bytes();
+        frame(id, [FRAME_TEMPERATURE, zone, high, low, 0, 0, 0, 0])
+    }
+
+    fn window_frame(zone: u8, open: bool) -> CanFrame {
+        frame(NODE_ID, [FRAME_WINDOW, zone, open as u8, 0, 0, 0, 0, 0])
+    }
+
+    /// Active heater, the left zone in `left` and the right one off
+    async fn heater(config: HeaterConfig, left: HeaterState) -> (HeaterNode, TestBus) {
+        let (mut heater, mut bus) = (HeaterNode::new(config), TestBus::default());
+        heater
+            .handle_action(&mut bus, &HeaterAction::HeaterPower(left, HeaterState::Off))
+            .await
+            .unwrap();
+        heater
+            .handle_action(&mut bus, &HeaterAction::SetActive(true))
+            .await
+            .unwrap();
+        heater.take_events();
+        bus.sent.clear();
+        (heater, bus)
+    }
+
+    fn without_thermostat() -> HeaterConfig {
+        HeaterConfig {
+            thermostat: false,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn status_frames_are_decoded() {
+        let (mut heater, mut bus) = (HeaterNode::new(without_thermostat()), TestBus::default());
+
+        heater
+            .handle_frame(
+                &mut bus,
+                &frame(NODE_ID, [FRAME_STATUS, 1, 2, 0x01, 0, 0, 0, 0]),
+            )
+            .await
+            .unwrap();
+
+        let [left, right] = &heater.zones;
+        assert_eq!(
+            (left.state, left.setpoint, left.output),
+            (HeaterState::Comfort, Some(20.0), true)
+        );
+        assert_eq!(
+            (right.state, right.setpoint, right.output),
+            (HeaterState::Eco, Some(17.0), false)
+        );
+        assert_eq!(
+            heater.take_events(),
+            [
+                DeviceEventKind::HeaterZoneChanged {
+                    zone: 0,
+                    state: HeaterState::Comfort
+                },
+                DeviceEventKind::HeaterZoneChanged {
+                    zone: 1,
+                    state: HeaterState::Eco
+                },
+            ]
+        );
+
+        let ret = heater
+            .handle_frame(
+                &mut bus,
+                &frame(NODE_ID, [FRAME_STATUS, 4, 0, 0, 0, 0, 0, 0]),
+            )
+            .await;
+        assert!(matches!(ret, Err(DeviceError::InvalidFrame)));
+    }
+
+    #[tokio::test]
+    async fn requested_states_are_encoded_while_active() {
+        let (mut heater, mut bus) = (HeaterNode::new(without_thermostat()), TestBus::default());
+
+        heater
+            .handle_action(
+                &mut bus,
+                &HeaterAction::HeaterPower(HeaterState::Comfort, HeaterState::AntiFreeze),
+            )
+            .await
+            .unwrap();
+        heater
+            .handle_action(&mut bus, &HeaterAction::SetActive(true))
+            .await
+            .unwrap();
+        heater
+            .handle_action(&mut bus, &HeaterAction::SetActive(false))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            bus.sent,
+            [
+                [CMD_SET_ZONES, 0, 0, 0, 0, 0, 0, 0],
+                [CMD_SET_ZONES, 1, 3, 0, 0, 0, 0, 0],
+                [CMD_SET_ZONES, 0, 0, 0, 0, 0, 0, 0],
+            ]
+        );
+        assert!(heater
+            .take_events()
+            .contains(&DeviceEventKind::HeaterActiveChanged { active: true }));
+    }
+
+    #[tokio::test]
+    async fn thermostat_follows_the_hysteresis() {
+        let config = HeaterConfig {
+            hysteresis: 1.0,
+            min_on_time: 0,
+            min_off_time: 0,
+            window: WindowConfig {
+                detection: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut heater, mut bus) = heater(config, HeaterState::Comfort).await;
+
+        // comfort is 20 °C, the output switches at 19.5 and 20.5
+        for (temperature, output) in [
+            (19.6, false),
+            (19.5, true),
+            (20.4, true),
+            (20.5, false),
+            (19.6, false),
+        ] {
+            heater
+                .handle_frame(&mut bus, &temperature_frame(NODE_ID, 0, temperature))
+                .await
+                .unwrap();
+            assert_eq!(heater.zones[0].output, output, "at {} °C", temperature);
+        }
+        assert_eq!(
+            bus.sent,
+            [
+                [CMD_SET_OUTPUTS, 0x01, 0, 0, 0, 0, 0, 0],
+                [CMD_SET_OUTPUTS, 0x00, 0, 0, 0, 0, 0, 0],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn thermostat_keeps_the_minimum_times() {
+        let config = HeaterConfig {
+            min_on_time: 300,
+            min_off_time: 0,
+            window: WindowConfig {
+                detection: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut heater, mut bus) = heater(config, HeaterState::Comfort).await;
+
+        heater
+            .handle_frame(&mut bus, &temperature_frame(NODE_ID, 0, 18.0))
+            .await
+            .unwrap();
+        assert!(heater.zones[0].output);
+
+        // too hot, but just switched on
+        heater
+            .handle_frame(&mut bus, &temperature_frame(NODE_ID, 0, 22.0))
+            .await
+            .unwrap();
+        assert!(heater.zones[0].output);
+        assert_eq!(bus.sent.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn thermostat_waits_for_the_minimum_off_time() {
+        let config = HeaterConfig {
+            window: WindowConfig {
+                detection: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // off since the status answering the activation
+        let (mut heater, mut bus) = heater(config, HeaterState::Comfort).await;
+
+        heater
+            .handle_frame(&mut bus, &temperature_frame(NODE_ID, 0, 18.0))
+            .await
+            .unwrap();
+        assert!(!heater.zones[0].output);
+        assert!(bus.sent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sensor_readings_take_precedence() {
+        let config = HeaterConfig {
+            sensors: vec![ZoneSensor {
+                zone: 1,
+                id: SENSOR_ID,
+            }],
+            ..without_thermostat()
+        };
+        let (mut heater, mut bus) = heater(config, HeaterState::Comfort).await;
+        assert!(heater.owns_frame(SENSOR_ID));
+
+        // the zone byte of the sensor nodes is ignored
+        heater
+            .handle_frame(&mut bus, &temperature_frame(SENSOR_ID, 0, 18.0))
+            .await
+            .unwrap();
+        // the heater node measures the other zone only
+        heater
+            .handle_frame(&mut bus, &temperature_frame(NODE_ID, 1, 25.0))
+            .await
+            .unwrap();
+        heater
+            .handle_frame(&mut bus, &temperature_frame(NODE_ID, 0, 21.0))
+            .await
+            .unwrap();
+
+        assert_eq!(heater.zones[0].temperature, Some(21.0));
+        assert_eq!(heater.zones[1].temperature, Some(18.0));
+
+        let ret = heater
+            .handle_frame(&mut bus, &temperature_frame(NODE_ID, 2, 21.0))
+            .await;
+        assert!(matches!(ret, Err(DeviceError::InvalidFrame)));
+    }
+
+    #[tokio::test]
+    async fn window_contact_saves_the_request() {
+        let (mut heater, mut bus) = heater(without_thermostat(), HeaterState::Comfort).await;
+
+        heater
+            .handle_frame(&mut bus, &window_frame(0, true))
+            .await
+            .unwrap();
+        let zone = &heater.zones[0];
+        assert!(zone.window_open);
+        assert_eq!(zone.requested, HeaterState::AntiFreeze);
+        assert_eq!(zone.saved_request, Some(HeaterState::Comfort));
+        assert_eq!(bus.sent, [[CMD_SET_ZONES, 3, 0, 0, 0, 0, 0, 0]]);
+
+        // changed while open, kept for when it closes
+        heater
+            .handle_action(
+                &mut bus,
+                &HeaterAction::HeaterPower(HeaterState::Eco, HeaterState::Comfort),
+            )
+            .await
+            .unwrap();
+        assert_eq!(heater.zones[0].requested, HeaterState::AntiFreeze);
+        assert_eq!(heater.zones[0].wanted(), HeaterState::Eco);
+        assert_eq!(heater.zones[1].requested, HeaterState::Comfort);
+
+        heater
+            .handle_frame(&mut bus, &window_frame(0, false))
+            .await
+            .unwrap();
+        let zone = &heater.zones[0];
+        assert!(!zone.window_open);
+        assert_eq!(zone.requested, HeaterState::Eco);
+        assert_eq!(zone.saved_request, None);
+        assert_eq!(bus.sent.last(), Some(&[CMD_SET_ZONES, 2, 1, 0, 0, 0, 0, 0]));
+
+        let events = heater.take_events();
+        assert!(events.contains(&DeviceEventKind::HeaterWindowOpened {
+            zone: 0,
+            cause: WindowCause::Contact
+        }));
+        assert!(events.contains(&DeviceEventKind::HeaterWindowClosed { zone: 0 }));
+    }
+
+    #[tokio::test]
+    async fn temperature_drop_opens_the_window_for_a_while() {
+        let config = HeaterConfig {
+            window: WindowConfig {
+                duration: 0,
+                ..Default::default()
+            },
+            ..without_thermostat()
+        };
+        let (mut heater, mut bus) = heater(config, HeaterState::Comfort).await;
+
+        for temperature in [20.0, 19.5, 19.0] {
+            heater
+                .handle_frame(&mut bus, &temperature_frame(NODE_ID, 0, temperature))
+                .await
+                .unwrap();
+        }
+        assert_eq!(heater.zones[0].requested, HeaterState::AntiFreeze);
+        assert!(heater
+            .take_events()
+            .contains(&DeviceEventKind::HeaterWindowOpened {
+                zone: 0,
+                cause: WindowCause::Temperature
+            }));
+
+        // closed by the next tick, the duration being over
+        heater.tick(&mut bus).await.unwrap();
+        assert!(!heater.zones[0].window_open);
+        assert_eq!(heater.zones[0].requested, HeaterState::Comfort);
+    }
+
+    #[tokio::test]
+    async fn schedule_failures_are_reported_once() {
+        let (mut heater, mut bus) = heater(without_thermostat(), HeaterState::Off).await;
+        heater.schedule = Some(HeaterSchedule::default());
+        bus.offline = true;
+
+        assert!(heater.tick(&mut bus).await.is_err());
+        assert!(heater.tick(&mut bus).await.is_err());
+        let scheduled = heater.take_scheduled();
+        assert_eq!(scheduled.len(), 1);
+        assert_eq!(
+            scheduled[0].0,
+            ("heater_power", json!({"left": "eco", "right": "eco"}))
+        );
+        assert!(matches!(scheduled[0].1, Err(DeviceError::Timeout)));
+
+        bus.offline = false;
+        heater.tick(&mut bus).await.unwrap();
+        heater.tick(&mut bus).await.unwrap();
+        let scheduled = heater.take_scheduled();
+        assert_eq!(scheduled.len(), 1);
+        assert!(scheduled[0].1.is_ok());
+        assert_eq!(heater.zones[0].state, HeaterState::Eco);
     }
 }
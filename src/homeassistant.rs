@@ -0,0 +1,335 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    alarm::{AlarmState, ArmMode},
+    device::{DeviceError, DeviceSnapshot, NodeSnapshot},
+    heater::{HeaterState, ZONE_COUNT},
+    mqtt::MqttTopics,
+};
+
+/// Home Assistant MQTT discovery, see
+/// https://www.home-assistant.io/integrations/mqtt/#mqtt-discovery
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HomeAssistantConfig {
+    pub discovery: bool,
+    pub prefix: String, // discovery prefix, as configured in Home Assistant
+}
+
+impl Default for HomeAssistantConfig {
+    fn default() -> HomeAssistantConfig {
+        HomeAssistantConfig {
+            discovery: true,
+            prefix: "homeassistant".to_string(),
+        }
+    }
+}
+
+const ALARM_STATES: [(AlarmState, &str); 6] = [
+    (AlarmState::Disarmed, "disarmed"),
+    (AlarmState::ArmingExit, "arming"),
+    (AlarmState::ArmedAway, "armed_away"),
+    (AlarmState::ArmedStay, "armed_home"),
+    (AlarmState::EntryDelay, "pending"),
+    (AlarmState::Triggered, "triggered"),
+];
+
+const LIGHTS: [(&str, &str); 2] = [("front", "Front light"), ("rear", "Rear light")];
+
+// the climate modes are "off" and "heat", the heating states are presets
+const HEATER_PRESETS: [HeaterState; 3] = [
+    HeaterState::Comfort,
+    HeaterState::Eco,
+    HeaterState::AntiFreeze,
+];
+
+/// Discovery message, published retained on `topic`
+#[derive(Debug)]
+pub struct DiscoveryConfig {
+    pub topic: String,
+    pub config: Value,
+}
+
+/// Command of a Home Assistant entity, as named actions of the device
+pub type EntityCommand = Vec<(String, Value)>;
+
+/// Alarm panel command, sent with `command_template`
+#[derive(Debug, Deserialize)]
+struct AlarmCommand {
+    action: String,
+    #[serde(default)]
+    code: String,
+}
+
+/// Serialized name of a state, as in the snapshots
+fn name<T: serde::Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(Value::String(name)) => name,
+        _ => unreachable!("Unit variants are serialized as strings"),
+    }
+}
+
+/// Entities of the Home Assistant integration, for the devices of one bridge
+#[derive(Debug)]
+pub struct HomeAssistant {
+    pub config: HomeAssistantConfig,
+    node_id: String,
+}
+
+impl HomeAssistant {
+    pub fn new(config: HomeAssistantConfig, client_id: &str) -> HomeAssistant {
+        // allowed characters in the discovery topics
+        let node_id = client_id
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        HomeAssistant { config, node_id }
+    }
+
+    /// Home Assistant availability, republish the configs when it comes back
+    pub fn status_topic(&self) -> String {
+        format!("{}/status", self.config.prefix)
+    }
+
+    fn discovery_topic(&self, component: &str, object_id: &str) -> String {
+        format!(
+            "{}/{}/{}/{}/config",
+            self.config.prefix, component, self.node_id, object_id
+        )
+    }
+
+    /// Attributes shared by all the entities of a device
+    fn entity(&self, topics: &MqttTopics, device: &DeviceSnapshot, object_id: &str) -> Value {
+        let (model, name) = match &device.node {
+            NodeSnapshot::Alarm(_) => ("Alarm", format!("Alarm {}", device.id)),
+            NodeSnapshot::Heater(_) => ("Heater", format!("Heater {}", device.id)),
+            NodeSnapshot::Other(_) => ("Device", format!("Device {}", device.id)),
+        };
+        let mut ha_device = json!({
+            "identifiers": [format!("{}_{}", self.node_id, device.id)],
+            "name": name,
+            "model": model,
+        });
+        // null is rejected, the version is only known once read
+        if let Some(version) = &device.version {
+            ha_device["sw_version"] = json!(version);
+        }
+
+        json!({
+            "unique_id": format!("{}_{}", self.node_id, object_id),
+            "object_id": object_id,
+            "device": ha_device,
+            "availability": [
+                { "topic": topics.status() },
+                {
+                    "topic": topics.state(device.id),
+                    "value_template": "{{ 'online' if value_json.online else 'offline' }}",
+                },
+            ],
+            "availability_mode": "all",
+        })
+    }
+
+    /// Discovery messages of the entities of a device, none for unknown types
+    pub fn discovery(&self, topics: &MqttTopics, device: &DeviceSnapshot) -> Vec<DiscoveryConfig> {
+        match &device.node {
+            NodeSnapshot::Alarm(_) => self.alarm_discovery(topics, device),
+            NodeSnapshot::Heater(_) => self.heater_discovery(topics, device),
+            NodeSnapshot::Other(_) => Vec::new(),
+        }
+    }
+
+    fn alarm_discovery(
+        &self,
+        topics: &MqttTopics,
+        device: &DeviceSnapshot,
+    ) -> Vec<DiscoveryConfig> {
+        let id = device.id;
+        let states = ALARM_STATES
+            .iter()
+            .map(|(state, ha_state)| format!("'{}': '{}'", name(state), ha_state))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let object_id = format!("alarm_{}", id);
+        let mut panel = self.entity(topics, device, &object_id);
+        panel["name"] = json!(null); // named after the device
+        panel["state_topic"] = json!(topics.state(id));
+        panel["value_template"] = json!(format!(
+            "{{% set states = {{{}}} %}}{{{{ states[value_json.state] }}}}",
+            states
+        ));
+        panel["command_topic"] = json!(topics.ha_command(id, "alarm"));
+        panel["command_template"] = json!(r#"{"action": "{{ action }}", "code": "{{ code }}"}"#);
+        panel["supported_features"] = json!(["arm_home", "arm_away"]);
+        // codes are checked by the node, and may not be required
+        panel["code"] = json!("REMOTE_CODE");
+        panel["code_arm_required"] = json!(false);
+        panel["code_disarm_required"] = json!(false);
+
+        let mut configs = vec![DiscoveryConfig {
+            topic: self.discovery_topic("alarm_control_panel", &object_id),
+            config: panel,
+        }];
+
+        for (light, light_name) in LIGHTS {
+            let object_id = format!("alarm_{}_light_{}", id, light);
+            let mut switch = self.entity(topics, device, &object_id);
+            switch["name"] = json!(light_name);
+            switch["icon"] = json!("mdi:lightbulb");
+            switch["state_topic"] = json!(topics.state(id));
+            switch["value_template"] = json!(format!(
+                "{{{{ 'ON' if value_json.lights.{} else 'OFF' }}}}",
+                light
+            ));
+            switch["command_topic"] = json!(topics.ha_command(id, &format!("light_{}", light)));
+            configs.push(DiscoveryConfig {
+                topic: self.discovery_topic("switch", &object_id),
+                config: switch,
+            });
+        }
+
+        configs
+    }
+
+    fn heater_discovery(
+        &self,
+        topics: &MqttTopics,
+        device: &DeviceSnapshot,
+    ) -> Vec<DiscoveryConfig> {
+        let id = device.id;
+        let presets: Vec<String> = HEATER_PRESETS.iter().map(name).collect();
+
+        (0..ZONE_COUNT)
+            .map(|zone| {
+                let object_id = format!("heater_{}_zone{}", id, zone);
+                let state = format!("value_json.zones[{}].state", zone);
+                let mut climate = self.entity(topics, device, &object_id);
+                climate["name"] = json!(format!("Zone {}", zone));
+                climate["temperature_unit"] = json!("C");
+                climate["precision"] = json!(0.1);
+                climate["modes"] = json!(["off", "heat"]);
+                climate["mode_state_topic"] = json!(topics.state(id));
+                climate["mode_state_template"] =
+                    json!(format!("{{{{ 'off' if {} == 'off' else 'heat' }}}}", state));
+                climate["mode_command_topic"] =
+                    json!(topics.ha_command(id, &format!("zone{}/mode", zone)));
+                climate["preset_modes"] = json!(presets);
+                climate["preset_mode_state_topic"] = json!(topics.state(id));
+                climate["preset_mode_value_template"] =
+                    json!(format!("{{{{ 'None' if {0} == 'off' else {0} }}}}", state));
+                climate["preset_mode_command_topic"] =
+                    json!(topics.ha_command(id, &format!("zone{}/preset", zone)));
+                climate["current_temperature_topic"] = json!(topics.state(id));
+                climate["current_temperature_template"] =
+                    json!(format!("{{{{ value_json.zones[{}].temperature }}}}", zone));
+                climate["action_topic"] = json!(topics.state(id));
+                climate["action_template"] = json!(format!(
+                    "{{{{ 'off' if {0} == 'off' else ('heating' if value_json.zones[{1}].output else 'idle') }}}}",
+                    state, zone
+                ));
+
+                DiscoveryConfig {
+                    topic: self.discovery_topic("climate", &object_id),
+                    config: climate,
+                }
+            })
+            .collect()
+    }
+
+    /// Translate the command of an entity, completed from the device state
+    pub fn command(
+        &self,
+        device: &DeviceSnapshot,
+        entity: &str,
+        payload: &[u8],
+    ) -> Result<EntityCommand, DeviceError> {
+        match &device.node {
+            NodeSnapshot::Alarm(alarm) => match entity {
+                "alarm" => {
+                    let command: AlarmCommand =
+                        serde_json::from_slice(payload).map_err(|_| DeviceError::InvalidArgs)?;
+                    let mut args = match command.action.as_str() {
+                        "ARM_AWAY" => json!({ "mode": name(&ArmMode::Away) }),
+                        "ARM_HOME" => json!({ "mode": name(&ArmMode::Stay) }),
+                        "DISARM" => json!({}),
+                        _ => return Err(DeviceError::Unsupported),
+                    };
+                    if !command.code.is_empty() {
+                        args["code"] = json!(command.code);
+                    }
+                    let action = if command.action == "DISARM" {
+                        "disarm"
+                    } else {
+                        "arm"
+                    };
+                    Ok(vec![(action.to_string(), args)])
+                }
+                "light_front" | "light_rear" => {
+                    let on = parse_switch(payload)?;
+                    let mut lights = alarm.lights;
+                    match entity {
+                        "light_front" => lights.front = on,
+                        _ => lights.rear = on,
+                    }
+                    Ok(vec![(
+                        "power_lights".to_string(),
+                        json!({ "front": lights.front, "rear": lights.rear }),
+                    )])
+                }
+                _ => Err(DeviceError::Unsupported),
+            },
+            NodeSnapshot::Heater(heater) => {
+                let (zone, command) = entity.split_once('/').ok_or(DeviceError::Unsupported)?;
+                let zone: usize = zone
+                    .strip_prefix("zone")
+                    .and_then(|zone| zone.parse().ok())
+                    .filter(|zone| *zone < ZONE_COUNT)
+                    .ok_or(DeviceError::Unsupported)?;
+                let payload = std::str::from_utf8(payload).map_err(|_| DeviceError::InvalidArgs)?;
+
+                let mut states: Vec<HeaterState> =
+                    heater.zones.iter().map(|zone| zone.wanted()).collect();
+                states[zone] = match (command, payload) {
+                    ("mode", "off") => HeaterState::Off,
+                    // keep the current preset, if any
+                    ("mode", "heat") if states[zone] != HeaterState::Off => states[zone],
+                    ("mode", "heat") => HeaterState::Comfort,
+                    ("preset", preset) => HEATER_PRESETS
+                        .into_iter()
+                        .find(|state| name(state) == preset)
+                        .ok_or(DeviceError::InvalidArgs)?,
+                    ("mode", _) => return Err(DeviceError::InvalidArgs),
+                    _ => return Err(DeviceError::Unsupported),
+                };
+
+                let mut actions = Vec::new();
+                // the zone states are only applied while the heater is active
+                if !heater.active && states[zone] != HeaterState::Off {
+                    actions.push(("set_active".to_string(), json!({ "active": true })));
+                }
+                actions.push((
+                    "heater_power".to_string(),
+                    json!({ "left": states[0], "right": states[1] }),
+                ));
+                Ok(actions)
+            }
+            NodeSnapshot::Other(_) => Err(DeviceError::Unsupported),
+        }
+    }
+}
+
+fn parse_switch(payload: &[u8]) -> Result<bool, DeviceError> {
+    match payload {
+        b"ON" => Ok(true),
+        b"OFF" => Ok(false),
+        _ => Err(DeviceError::InvalidArgs),
+    }
+}
@@ -0,0 +1,114 @@
+//! Controller of CAN bus nodes (alarm, heater...), with a REST API.
+//!
+//! - [`can`]: access to the CAN bus, [`can::CanInterface`]
+//! - [`controller`]: the [`controller::Controller`] task owning the devices,
+//!   driven through a [`controller::ControllerHandle`]
+//! - [`audit`]: the log of the actions run on the devices, by origin
+//! - [`rules`]: the automation rules, run by the controller on its events
+//! - [`device`]: the device traits, implemented by the node types such as
+//!   [`alarm::AlarmNode`] and [`heater::HeaterNode`], and the typed
+//!   [`device::DeviceHandle`]
+//! - [`registry`]: the device types known to the controller, to register
+//!   out-of-tree ones
+//! - `webserver`: the Rocket REST API and its dashboard, with the `web`
+//!   feature, its bodies being in [`api`] for the clients and its roles in
+//!   [`auth`]
+//! - [`rpc`]: JSON-RPC over a local Unix socket, for local management
+//! - `mqtt`: the MQTT bridge, with the `mqtt` feature, and its
+//!   `homeassistant` discovery
+//!
+//! A minimal application, as done by the `poc-rust-arch` binary with the
+//! `web` feature:
+//!
+//! ```no_run
+//! # #[cfg(feature = "web")]
+//! # fn main() {
+//! use poc_rust_arch::{
+//!     can::CanInterface, config::Config, controller::{run_controller, Controller},
+//!     registry::DeviceRegistry, shared::Shared, shutdown::Shutdown, webserver::run_webserver,
+//! };
+//! use std::sync::Arc;
+//! use tokio::sync::broadcast;
+//!
+//! let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+//! let (notify_shutdown, _) = broadcast::channel(1);
+//! let config = Config::default();
+//!
+//! let mut controller = Controller::new(
+//!     &rt,
+//!     CanInterface::new(config.can).unwrap(),
+//!     config.controller,
+//!     DeviceRegistry::with_builtin(),
+//!     Shutdown::new(notify_shutdown.subscribe()),
+//! );
+//! controller.add_configured_devices().unwrap();
+//! let shared = Arc::new(Shared::new(controller.get_handle()));
+//!
+//! let web_shutdown = Shutdown::new(notify_shutdown.subscribe());
+//! rt.spawn(async move {
+//!     tokio::signal::ctrl_c().await.unwrap();
+//!     let _ = notify_shutdown.send(());
+//! });
+//!
+//! rt.spawn(run_controller(controller));
+//! rt.block_on(run_webserver(config.web, shared, web_shutdown));
+//! # }
+//! # #[cfg(not(feature = "web"))]
+//! # fn main() {}
+//! ```
+//!
+//! # Feature flags
+//!
+//! - `web` (default): the `webserver` module and its Rocket dependency.
+//!   Without it, the controller runs headless, driven through its handle.
+//! - `storage` (default): the event history, the audit log and the device
+//!   states (schedules, energy counters...) are saved to the configured
+//!   files. Without it, they are kept in memory only and the paths are
+//!   ignored.
+//! - `ctl`: the `ctl` command-line client of the REST API.
+//! - `openapi` (default): the `openapi` module, the OpenAPI document of the
+//!   REST API and its Swagger UI.
+//! - `socketcan`: the Linux SocketCAN bus, used unless `can.simulated` is
+//!   set. Without it, only the simulated loopback bus is available.
+//! - `tls`: HTTPS for the web server when `[web.tls]` is present, with
+//!   optional client certificates mapped to API roles.
+//! - `mqtt`: the `mqtt` module, bridging the devices to a MQTT broker when
+//!   the `[mqtt]` config section is present.
+
+#[cfg(feature = "web")]
+#[macro_use]
+extern crate rocket;
+
+pub mod action;
+pub mod alarm;
+pub mod api;
+pub mod audit;
+pub mod auth;
+pub mod can;
+pub mod config;
+pub mod controller;
+#[cfg(feature = "web")]
+mod dashboard;
+pub mod device;
+pub mod energy;
+pub mod event;
+pub mod heater;
+#[cfg(feature = "mqtt")]
+pub mod homeassistant;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "openapi")]
+pub mod openapi;
+pub mod registry;
+pub mod rpc;
+pub mod rules;
+pub mod schedule;
+pub mod secret;
+pub mod shared;
+pub mod shutdown;
+#[cfg(feature = "socketcan")]
+mod socketcan;
+pub mod storage;
+mod utils;
+#[cfg(feature = "web")]
+pub mod webserver;
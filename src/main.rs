@@ -1,24 +1,18 @@
-#[macro_use]
-extern crate rocket;
-
-mod alarm;
-mod can;
-mod controller;
-mod traits;
-mod device;
-mod heater;
-mod shared;
-mod shutdown;
-mod utils;
-mod webserver;
-
+use std::path::PathBuf;
+#[cfg(feature = "web")]
 use std::sync::Arc;
 
-use shutdown::Shutdown;
+#[cfg(feature = "mqtt")]
+use poc_rust_arch::mqtt::run_mqtt;
+use poc_rust_arch::{
+    can, config::Config, controller, controller::run_controller, registry::DeviceRegistry,
+    rpc::run_rpc, shutdown::Shutdown,
+};
+#[cfg(feature = "web")]
+use poc_rust_arch::{shared, webserver};
 use tokio::sync::broadcast;
 
-use can::CanConfig;
-use controller::{run_controller, ControllerConfig};
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
 
 fn main() {
     let rt = tokio::runtime::Builder::new_current_thread()
@@ -29,37 +23,97 @@ fn main() {
 
     let (notify_shutdown, _) = broadcast::channel(1);
 
-    let can_config = CanConfig::default();
-    let controller_config = ControllerConfig::default();
+    // config file path can be given as first argument
+    let config_path = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+    let config = if config_path.exists() {
+        Config::load(&config_path).unwrap_or_else(|err| {
+            eprintln!("{}: {}", config_path.display(), err);
+            std::process::exit(1);
+        })
+    } else {
+        println!("No config file found, using defaults");
+        Config::default()
+    };
 
-    let can_iface = can::CanInterface::new(can_config);
+    let iface_name = config.can.iface.clone();
+    let can_iface = can::CanInterface::new(config.can).unwrap_or_else(|err| {
+        eprintln!("Failed to open CAN interface {}: {}", iface_name, err);
+        std::process::exit(1);
+    });
     let mut controller = controller::Controller::new(
         &rt,
         can_iface,
-        controller_config,
+        config.controller,
+        DeviceRegistry::with_builtin(),
         Shutdown::new(notify_shutdown.subscribe()),
     );
-    let controller_handle = controller.get_handle();
+    if let Err(err) = controller.add_configured_devices() {
+        eprintln!("{}: {}", config_path.display(), err);
+        std::process::exit(1);
+    }
 
-    let alarm_handle = controller.get_device_alarm_handle();
-    let heater_handle = controller.get_device_heater_handle();
+    #[cfg(feature = "mqtt")]
+    let h_mqtt = config.mqtt.map(|mqtt| {
+        rt.spawn(run_mqtt(
+            mqtt,
+            controller.get_handle(),
+            Shutdown::new(notify_shutdown.subscribe()),
+        ))
+    });
+
+    let h_rpc = config.rpc.map(|rpc| {
+        rt.spawn(run_rpc(
+            rpc,
+            controller.get_handle(),
+            Shutdown::new(notify_shutdown.subscribe()),
+        ))
+    });
 
-    let shared = Arc::new(shared::Shared::new(controller_handle));
+    #[cfg(feature = "web")]
+    let web_shutdown = Shutdown::new(notify_shutdown.subscribe());
 
     rt.spawn(async move {
         tokio::signal::ctrl_c()
             .await
             .expect("Failed to install CTRL+C signal handler");
 
-        info!("CTRL+C received, shutting down...");
+        println!("CTRL+C received, shutting down...");
 
         let _ = notify_shutdown.send(());
     });
 
+    #[cfg(feature = "web")]
+    let shared = Arc::new(shared::Shared::new(controller.get_handle()));
+
     let h_ctrl = rt.spawn(run_controller(controller));
-    let h_web =
-        rt.spawn(webserver::webserver("0.0.0.0".to_string(), 8091, shared.clone()).launch());
 
-    // terminate on ctrl-c (handled by rocket)
-    let _ = rt.block_on(async move { tokio::join!(h_web, h_ctrl) });
+    #[cfg(feature = "web")]
+    {
+        let h_web = rt.spawn(webserver::run_webserver(
+            config.web,
+            shared.clone(),
+            web_shutdown,
+        ));
+
+        // terminate on ctrl-c, once the web server and the controller stopped
+        let _ = rt.block_on(async move { tokio::join!(h_web, h_ctrl) });
+    }
+
+    // headless: terminate on ctrl-c, once the controller stopped
+    #[cfg(not(feature = "web"))]
+    let _ = rt.block_on(h_ctrl);
+
+    // let the control socket be removed
+    if let Some(h_rpc) = h_rpc {
+        let _ = rt.block_on(h_rpc);
+    }
+
+    // let the bridge publish its offline status
+    #[cfg(feature = "mqtt")]
+    if let Some(h_mqtt) = h_mqtt {
+        let _ = rt.block_on(h_mqtt);
+    }
 }
@@ -0,0 +1,392 @@
+use chrono::Utc;
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeSet, time::Duration};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc},
+    time,
+};
+
+use crate::{
+    audit::{AuditRecord, Origin},
+    auth::{named_action_role, Role},
+    controller::ControllerHandle,
+    device::{DeviceError, DeviceSnapshot},
+    event::DeviceEvent,
+    homeassistant::{HomeAssistant, HomeAssistantConfig},
+    shutdown::Shutdown,
+};
+
+const REQUESTS_CAPACITY: usize = 64;
+const INCOMING_CAPACITY: usize = 16;
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+const STATUS_ONLINE: &str = "online";
+const STATUS_OFFLINE: &str = "offline";
+const PERMISSION_DENIED: &str = "Permission denied";
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub prefix: String,    // topics root, `<prefix>/<device_id>/...`
+    pub keep_alive: u64,   // in s
+    pub reconnect: u64,    // in s, delay before reconnecting to the broker
+    pub state_period: u64, // in s, all the states are republished
+    pub role: Role,        // of the broker clients, as for the web API
+    pub homeassistant: HomeAssistantConfig,
+}
+
+impl Default for MqttConfig {
+    fn default() -> MqttConfig {
+        MqttConfig {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "poc-rust-arch".to_string(),
+            username: None,
+            password: None,
+            prefix: "poc-rust-arch".to_string(),
+            keep_alive: 30,
+            reconnect: 5,
+            state_period: 60,
+            role: Role::Operator,
+            homeassistant: HomeAssistantConfig::default(),
+        }
+    }
+}
+
+/// Command received on `<prefix>/<device_id>/set`, a named action
+#[derive(Debug, Deserialize)]
+struct MqttCommand {
+    action: String,
+    #[serde(default)]
+    args: Value,
+}
+
+/// Outcome of a command, published on `<prefix>/<device_id>/result`
+#[derive(Debug, Serialize)]
+struct MqttCommandResult {
+    action: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Topics of the bridge, under the configured prefix
+#[derive(Debug, Clone)]
+pub struct MqttTopics {
+    pub prefix: String,
+}
+
+impl MqttTopics {
+    /// Bridge availability, `online` or `offline` (last will), retained
+    pub fn status(&self) -> String {
+        format!("{}/status", self.prefix)
+    }
+
+    /// Device snapshot, retained
+    pub fn state(&self, id: u32) -> String {
+        format!("{}/{}/state", self.prefix, id)
+    }
+
+    /// Device events, as recorded in the history
+    pub fn event(&self, id: u32) -> String {
+        format!("{}/{}/event", self.prefix, id)
+    }
+
+    /// Commands to the device: `{"action": "arm", "args": {"mode": "away"}}`
+    pub fn set(&self, id: u32) -> String {
+        format!("{}/{}/set", self.prefix, id)
+    }
+
+    pub fn result(&self, id: u32) -> String {
+        format!("{}/{}/result", self.prefix, id)
+    }
+
+    /// Commands of the Home Assistant entities of the device
+    pub fn ha_command(&self, id: u32, entity: &str) -> String {
+        format!("{}/{}/ha/{}", self.prefix, id, entity)
+    }
+
+    /// Device id and command of a `set` or `ha` topic
+    fn parse_command<'a>(&self, topic: &'a str) -> Option<(u32, Command<'a>)> {
+        let rest = topic.strip_prefix(&self.prefix)?.strip_prefix('/')?;
+        let (id, command) = rest.split_once('/')?;
+        let command = match command {
+            "set" => Command::Set,
+            _ => Command::HomeAssistant(command.strip_prefix("ha/")?),
+        };
+        Some((id.parse().ok()?, command))
+    }
+}
+
+enum Command<'a> {
+    Set,
+    HomeAssistant(&'a str), // entity, and its command if several
+}
+
+/// Bridge between the controller and a MQTT broker
+struct MqttBridge {
+    client: AsyncClient,
+    topics: MqttTopics,
+    ctrl: ControllerHandle,
+    homeassistant: Option<HomeAssistant>,
+    announced: BTreeSet<u32>, // devices with their discovery configs published
+    role: Role,
+}
+
+impl MqttBridge {
+    async fn handle_packet(&mut self, packet: Packet) {
+        match packet {
+            Packet::ConnAck(_) => {
+                println!("MQTT connected");
+                // subscriptions are lost with the session
+                self.subscribe(format!("{}/+/set", self.topics.prefix));
+                if let Some(homeassistant) = &self.homeassistant {
+                    self.subscribe(format!("{}/+/ha/#", self.topics.prefix));
+                    self.subscribe(homeassistant.status_topic());
+                }
+                self.publish(self.topics.status(), true, STATUS_ONLINE);
+                self.announced.clear();
+                self.publish_states().await;
+            }
+            Packet::Publish(publish) => {
+                if let Some(homeassistant) = &self.homeassistant {
+                    // Home Assistant restarted, it may have lost the configs
+                    if publish.topic == homeassistant.status_topic() {
+                        if publish.payload.as_ref() == STATUS_ONLINE.as_bytes() {
+                            self.announced.clear();
+                            self.publish_states().await;
+                        }
+                        return;
+                    }
+                }
+                match self.topics.parse_command(&publish.topic) {
+                    Some((id, Command::Set)) => self.handle_command(id, &publish.payload).await,
+                    Some((id, Command::HomeAssistant(entity))) => {
+                        self.handle_ha_command(id, entity, &publish.payload).await
+                    }
+                    None => println!("MQTT message on unexpected topic {}", publish.topic),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Run a command of a Home Assistant entity, as named actions
+    async fn handle_ha_command(&self, id: u32, entity: &str, payload: &[u8]) {
+        let Some(homeassistant) = &self.homeassistant else {
+            return;
+        };
+        let actions = match self.device(id).await {
+            Some(device) => homeassistant
+                .command(&device, entity, payload)
+                .map(|actions| (device, actions)),
+            None => Err(DeviceError::NotFound),
+        };
+        let (device, actions) = match actions {
+            Ok(actions) => actions,
+            Err(err) => {
+                println!("MQTT invalid {} command for device {}: {}", entity, id, err);
+                return;
+            }
+        };
+
+        for (action, args) in actions {
+            if !self.run_action(&device, action, args).await {
+                break;
+            }
+        }
+    }
+
+    async fn handle_command(&self, id: u32, payload: &[u8]) {
+        let command: MqttCommand = match serde_json::from_slice(payload) {
+            Ok(command) => command,
+            Err(err) => {
+                println!("MQTT invalid command for device {}: {}", id, err);
+                return;
+            }
+        };
+
+        match self.device(id).await {
+            Some(device) => {
+                self.run_action(&device, command.action, command.args).await;
+            }
+            None => self.publish_result(id, command.action, Err(DeviceError::NotFound.to_string())),
+        }
+    }
+
+    /// Run a named action if the role of the bridge allows it, the refusals
+    /// being audited, and publish its outcome
+    async fn run_action(&self, device: &DeviceSnapshot, action: String, args: Value) -> bool {
+        let ret = if self.role < named_action_role(&device.node, &action) {
+            self.ctrl
+                .record_audit(AuditRecord {
+                    timestamp: Utc::now(),
+                    origin: Origin::Mqtt,
+                    device_id: Some(device.id),
+                    action: action.clone(),
+                    args: Value::Null,
+                    error: Some(PERMISSION_DENIED.to_string()),
+                })
+                .await;
+            Err(PERMISSION_DENIED.to_string())
+        } else {
+            self.ctrl
+                .named_action(device.id, action.clone(), args, Origin::Mqtt)
+                .await
+                .map_err(|err| err.to_string())
+        };
+
+        let ok = ret.is_ok();
+        self.publish_result(device.id, action, ret);
+        ok
+    }
+
+    fn publish_result(&self, id: u32, action: String, ret: Result<(), String>) {
+        let result = MqttCommandResult {
+            action,
+            ok: ret.is_ok(),
+            error: ret.err(),
+        };
+        self.publish(self.topics.result(id), false, json(&result));
+    }
+
+    async fn device(&self, id: u32) -> Option<DeviceSnapshot> {
+        let devices = self.ctrl.get_devices().await;
+        devices.into_iter().find(|device| device.id == id)
+    }
+
+    async fn publish_event(&mut self, event: &DeviceEvent) {
+        self.publish(self.topics.event(event.device_id), false, json(event));
+
+        if let Some(device) = self.device(event.device_id).await {
+            self.publish_state(&device);
+        }
+    }
+
+    async fn publish_states(&mut self) {
+        for device in self.ctrl.get_devices().await {
+            self.publish_state(&device);
+        }
+    }
+
+    /// Publish the device state, and its discovery configs first if needed
+    fn publish_state(&mut self, device: &DeviceSnapshot) {
+        if let Some(homeassistant) = &self.homeassistant {
+            if self.announced.insert(device.id) {
+                for discovery in homeassistant.discovery(&self.topics, device) {
+                    self.publish(discovery.topic, true, json(&discovery.config));
+                }
+            }
+        }
+        self.publish(self.topics.state(device.id), true, json(device));
+    }
+
+    fn subscribe(&self, filter: String) {
+        if let Err(err) = self.client.try_subscribe(&filter, QoS::AtLeastOnce) {
+            println!("MQTT subscribe to {} failed: {}", filter, err);
+        }
+    }
+
+    /// Queue a message, dropped if the broker is not keeping up
+    fn publish(&self, topic: String, retain: bool, payload: impl Into<Vec<u8>>) {
+        if let Err(err) = self
+            .client
+            .try_publish(&topic, QoS::AtLeastOnce, retain, payload)
+        {
+            println!("MQTT publish to {} dropped: {}", topic, err);
+        }
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> Vec<u8> {
+    serde_json::to_vec(value).expect("Failed to serialize MQTT payload")
+}
+
+/// Drive the connection, forwarding the incoming packets until disconnected
+async fn poll_connection(mut eventloop: EventLoop, incoming: mpsc::Sender<Packet>, reconnect: u64) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(packet)) => {
+                if incoming.send(packet).await.is_err() {
+                    break;
+                }
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+            Ok(Event::Outgoing(_)) => {}
+            Err(err) => {
+                println!("MQTT connection error: {}", err);
+                // the next poll reconnects
+                time::sleep(Duration::from_secs(reconnect)).await;
+            }
+        }
+    }
+}
+
+/// Publish the device states and events, and run the commands received,
+/// until shutdown
+pub async fn run_mqtt(config: MqttConfig, ctrl: ControllerHandle, mut shutdown: Shutdown) {
+    let topics = MqttTopics {
+        prefix: config.prefix.clone(),
+    };
+
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(config.keep_alive));
+    options.set_last_will(LastWill::new(
+        topics.status(),
+        STATUS_OFFLINE,
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+
+    let (client, eventloop) = AsyncClient::new(options, REQUESTS_CAPACITY);
+    let (send, mut incoming) = mpsc::channel(INCOMING_CAPACITY);
+    let h_conn = tokio::spawn(poll_connection(eventloop, send, config.reconnect));
+
+    let mut events = ctrl.subscribe().await;
+    // states are published once connected, then periodically
+    let state_period = Duration::from_secs(config.state_period);
+    let mut state_interval = time::interval_at(time::Instant::now() + state_period, state_period);
+    let homeassistant = config
+        .homeassistant
+        .discovery
+        .then(|| HomeAssistant::new(config.homeassistant, &config.client_id));
+    let mut bridge = MqttBridge {
+        client,
+        topics,
+        ctrl,
+        homeassistant,
+        announced: BTreeSet::new(),
+        role: config.role,
+    };
+
+    println!("MQTT bridge to {}:{}", config.host, config.port);
+    loop {
+        tokio::select! {
+            Some(packet) = incoming.recv() => bridge.handle_packet(packet).await,
+            event = events.recv() => match event {
+                Ok(event) => bridge.publish_event(&event).await,
+                Err(RecvError::Lagged(_)) => bridge.publish_states().await,
+                Err(RecvError::Closed) => break,
+            },
+            _ = state_interval.tick() => bridge.publish_states().await,
+            _ = shutdown.recv() => break,
+        }
+    }
+
+    println!("Shutting down MQTT bridge");
+    // the last will is only sent by the broker on connection loss
+    bridge.publish(bridge.topics.status(), true, STATUS_OFFLINE);
+    let _ = bridge.client.try_disconnect();
+    drop(incoming);
+    let _ = time::timeout(DISCONNECT_TIMEOUT, h_conn).await;
+}
@@ -0,0 +1,110 @@
+//! OpenAPI document of the REST API, generated from the routes and the serde
+//! types, served at `/openapi.json` along with a bundled Swagger UI at
+//! `/docs`.
+use utoipa::{
+    openapi::{
+        schema::{AdditionalProperties, ObjectBuilder, Type},
+        security::{Http, HttpAuthScheme, SecurityRequirement, SecurityScheme},
+        RefOr, Schema,
+    },
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::webserver::*;
+
+/// Basic and bearer authentication, see `auth::AuthConfig`
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "basic",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)),
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+        openapi.security = Some(vec![
+            SecurityRequirement::new("basic", Vec::<String>::new()),
+            SecurityRequirement::new("bearer", Vec::<String>::new()),
+        ]);
+    }
+}
+
+/// The snapshots of the out-of-tree device types are untagged, any object
+/// with its own `type` field
+struct OtherSnapshots;
+
+impl Modify for OtherSnapshots {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let Some(components) = openapi.components.as_mut() else {
+            return;
+        };
+        // `NodeSnapshot::Other` being the last variant
+        if let Some(RefOr::T(Schema::OneOf(one_of))) = components.schemas.get_mut("NodeSnapshot") {
+            if let Some(other) = one_of.items.last_mut() {
+                *other = ObjectBuilder::new()
+                    .description(Some("Out-of-tree device type"))
+                    .property("type", ObjectBuilder::new().schema_type(Type::String))
+                    .required("type")
+                    .additional_properties(Some(AdditionalProperties::FreeForm(true)))
+                    .into();
+            }
+        }
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        route_stats,
+        route_can_stats,
+        route_query,
+        route_events,
+        route_devices,
+        route_device,
+        route_device_history,
+        route_device_energy,
+        route_device_actions,
+        route_device_named_action,
+        route_dev_action,
+        route_device_reset,
+        route_device_identify,
+        route_device_ping,
+        route_device_version,
+        route_get_schedule,
+        route_set_schedule,
+        route_delete_schedule,
+        route_set_override,
+        route_delete_override,
+        route_set_away,
+        route_delete_away,
+        route_audit,
+        route_rules,
+        route_rule,
+        route_set_rule,
+        route_delete_rule,
+    ),
+    info(description = "Controller of CAN bus nodes (alarm, heater...). With authentication \
+        configured, viewers may read, operators run the heater and management actions, and \
+        admins the alarm actions besides the lights, the node resets, the audit log and \
+        the rules."),
+    modifiers(&Security, &OtherSnapshots),
+    tags(
+        (name = "controller", description = "Controller counters, raw node queries and audit log"),
+        (name = "devices", description = "Device states, history and energy"),
+        (name = "actions", description = "Actions of the device types, by name"),
+        (name = "management", description = "Management commands of every node"),
+        (name = "schedule", description = "Weekly programs of the heaters"),
+        (name = "rules", description = "Automation rules, run by the controller"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Swagger UI, serving the document
+pub fn swagger_ui() -> SwaggerUi {
+    SwaggerUi::new("/docs/<_..>").url("/openapi.json", ApiDoc::openapi())
+}
@@ -0,0 +1,226 @@
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use crate::{
+    alarm::AlarmNode,
+    device::{Device, DeviceControllableTrait, DeviceNodeTrait, DeviceTrait},
+    heater::HeaterNode,
+    rules::RuleError,
+};
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("Unknown device type: {0}")]
+    UnknownType(String),
+    #[error("Invalid {0} settings: {1}")]
+    InvalidSettings(&'static str, toml::de::Error),
+    #[error("Duplicate device id: {0}")]
+    DuplicateId(u32),
+    #[error("Invalid rule: {0}")]
+    Rule(#[from] RuleError),
+}
+
+/// Device type, built by the controller from its settings.
+///
+/// Out-of-tree device types implement it along with `DeviceTrait` and
+/// `DeviceControllableTrait`, and are registered at startup with
+/// `DeviceRegistry::register`.
+pub trait DevicePlugin: DeviceTrait + DeviceControllableTrait + 'static {
+    /// Type code announced by the nodes during discovery
+    const TYPE_CODE: u8;
+    /// Name of the type, and of its settings section in the config file
+    const TYPE_NAME: &'static str;
+
+    type Settings: DeserializeOwned + Default;
+
+    fn create(settings: Self::Settings) -> Self;
+}
+
+/// Settings of the device types, by type name
+pub type TypeSettings = toml::Table;
+
+type DeviceFactory =
+    fn(u32, Option<toml::Value>) -> Result<Box<dyn DeviceNodeTrait>, RegistryError>;
+
+fn create_device<D: DevicePlugin>(
+    id: u32,
+    settings: Option<toml::Value>,
+) -> Result<Box<dyn DeviceNodeTrait>, RegistryError> {
+    let settings = match settings {
+        Some(settings) => settings
+            .try_into()
+            .map_err(|err| RegistryError::InvalidSettings(D::TYPE_NAME, err))?,
+        None => D::Settings::default(),
+    };
+
+    Ok(Box::new(Device::with(id, D::create(settings))))
+}
+
+/// Settings section of the type `name`, with the settings of a single device
+/// merged over it, sub-tables key by key
+fn device_settings(
+    name: &str,
+    settings: &TypeSettings,
+    overrides: &toml::Table,
+) -> Option<toml::Value> {
+    if overrides.is_empty() {
+        return settings.get(name).cloned();
+    }
+    match settings.get(name) {
+        Some(toml::Value::Table(section)) => Some(toml::Value::Table(merge_settings(
+            section.clone(),
+            overrides,
+        ))),
+        // malformed, reported when deserialized
+        Some(section) => Some(section.clone()),
+        None => Some(toml::Value::Table(overrides.clone())),
+    }
+}
+
+fn merge_settings(mut base: toml::Table, overrides: &toml::Table) -> toml::Table {
+    for (key, value) in overrides {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(section)), toml::Value::Table(value)) => {
+                *section = merge_settings(std::mem::take(section), value);
+            }
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    base
+}
+
+#[derive(Debug)]
+struct DeviceType {
+    name: &'static str,
+    factory: DeviceFactory,
+}
+
+/// Device factories, by type code
+#[derive(Debug, Default)]
+pub struct DeviceRegistry {
+    types: BTreeMap<u8, DeviceType>,
+}
+
+impl DeviceRegistry {
+    /// Registry of the device types of this crate
+    pub fn with_builtin() -> DeviceRegistry {
+        let mut registry = DeviceRegistry::default();
+        registry.register::<AlarmNode>();
+        registry.register::<HeaterNode>();
+        registry
+    }
+
+    pub fn register<D: DevicePlugin>(&mut self) -> &mut DeviceRegistry {
+        // types are configured by name, which has to be unique as well
+        if let Some((code, _)) = self
+            .types
+            .iter()
+            .find(|(code, t)| t.name == D::TYPE_NAME && **code != D::TYPE_CODE)
+        {
+            panic!(
+                "Device type name {} registered for codes {:#04x} and {:#04x}",
+                D::TYPE_NAME,
+                code,
+                D::TYPE_CODE
+            );
+        }
+        let previous = self.types.insert(
+            D::TYPE_CODE,
+            DeviceType {
+                name: D::TYPE_NAME,
+                factory: create_device::<D>,
+            },
+        );
+        if let Some(previous) = previous {
+            panic!(
+                "Device type code {:#04x} registered by {} and {}",
+                D::TYPE_CODE,
+                previous.name,
+                D::TYPE_NAME
+            );
+        }
+        self
+    }
+
+    pub fn type_name(&self, code: u8) -> Option<&'static str> {
+        self.types.get(&code).map(|t| t.name)
+    }
+
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.find(name).is_some()
+    }
+
+    /// Check that every settings section is for a registered type
+    pub fn check_settings(&self, settings: &TypeSettings) -> Result<(), RegistryError> {
+        match settings.keys().find(|name| self.find(name).is_none()) {
+            Some(name) => Err(RegistryError::UnknownType(name.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Build a device of the type called `name`, from its settings section
+    /// and the device's own `overrides`
+    pub fn create(
+        &self,
+        name: &str,
+        id: u32,
+        settings: &TypeSettings,
+        overrides: &toml::Table,
+    ) -> Result<Box<dyn DeviceNodeTrait>, RegistryError> {
+        let device_type = self
+            .find(name)
+            .ok_or_else(|| RegistryError::UnknownType(name.to_string()))?;
+        (device_type.factory)(id, device_settings(name, settings, overrides))
+    }
+
+    /// Build a device from the type code it announced
+    pub fn create_by_code(
+        &self,
+        code: u8,
+        id: u32,
+        settings: &TypeSettings,
+    ) -> Result<Box<dyn DeviceNodeTrait>, RegistryError> {
+        let device_type = self
+            .types
+            .get(&code)
+            .ok_or_else(|| RegistryError::UnknownType(format!("{:#04x}", code)))?;
+        (device_type.factory)(id, settings.get(device_type.name).cloned())
+    }
+
+    fn find(&self, name: &str) -> Option<&DeviceType> {
+        self.types.values().find(|t| t.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_settings_override_type_settings() {
+        let settings: TypeSettings = toml::from_str(
+            "[heater]\npower = [1500.0, 1000.0]\n[heater.window]\ndetection = true\nduration = 1800\n",
+        )
+        .unwrap();
+        let overrides: toml::Table =
+            toml::from_str("power = [2000.0, 500.0]\nwindow = { duration = 600 }\n").unwrap();
+
+        let merged = device_settings("heater", &settings, &overrides).unwrap();
+        let expected: toml::Value =
+            toml::from_str("power = [2000.0, 500.0]\n[window]\ndetection = true\nduration = 600\n")
+                .unwrap();
+        assert_eq!(merged, expected);
+
+        assert_eq!(
+            device_settings("heater", &settings, &toml::Table::new()),
+            settings.get("heater").cloned()
+        );
+        assert_eq!(
+            device_settings("alarm", &settings, &overrides),
+            Some(toml::Value::Table(overrides))
+        );
+    }
+}
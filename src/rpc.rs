@@ -0,0 +1,548 @@
+use chrono::Utc;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs, io,
+    os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt},
+    path::PathBuf,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{unix::OwnedWriteHalf, UnixListener, UnixStream},
+    select,
+    sync::broadcast::{self, error::RecvError},
+};
+
+use crate::{
+    api::QueryResponse,
+    audit::{AuditRecord, Origin},
+    auth::{named_action_role, Role},
+    controller::ControllerHandle,
+    device::DeviceError,
+    event::DeviceEvent,
+    shutdown::Shutdown,
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct RpcConfig {
+    pub path: PathBuf,
+    pub mode: u32, // permissions of the socket file, who may connect
+    // peers with the roles of the web API, by uid or primary group, root
+    // and the daemon user being admins and the others viewers
+    pub admin_uids: Vec<u32>,
+    pub admin_gids: Vec<u32>,
+    pub operator_uids: Vec<u32>,
+    pub operator_gids: Vec<u32>,
+}
+
+impl Default for RpcConfig {
+    fn default() -> RpcConfig {
+        RpcConfig {
+            path: PathBuf::from("control.sock"),
+            mode: 0o660,
+            admin_uids: Vec::new(),
+            admin_gids: Vec::new(),
+            operator_uids: Vec::new(),
+            operator_gids: Vec::new(),
+        }
+    }
+}
+
+impl RpcConfig {
+    /// Role of a peer, `owner` being the uid of the socket file
+    fn peer_role(&self, owner: u32, uid: u32, gid: u32) -> Role {
+        if uid == 0
+            || uid == owner
+            || self.admin_uids.contains(&uid)
+            || self.admin_gids.contains(&gid)
+        {
+            Role::Admin
+        } else if self.operator_uids.contains(&uid) || self.operator_gids.contains(&gid) {
+            Role::Operator
+        } else {
+            Role::Viewer
+        }
+    }
+}
+
+// Longest request line, the connection is closed beyond
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const DEVICE_ERROR: i32 = -32000;
+const PERMISSION_DENIED: i32 = -32001;
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    id: Option<Value>, // none for notifications, not answered
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i32, message: impl Into<String>) -> RpcError {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<DeviceError> for RpcError {
+    fn from(err: DeviceError) -> RpcError {
+        RpcError::new(DEVICE_ERROR, err.to_string())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+/// Event pushed to the subscribed clients
+#[derive(Debug, Serialize)]
+struct RpcNotification<'a> {
+    jsonrpc: &'static str,
+    method: &'static str,
+    params: &'a DeviceEvent,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryParams {
+    id: u32,
+    timeout: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceParams {
+    id: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct ActionParams {
+    id: u32,
+    name: String,
+    #[serde(default)]
+    args: Value,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SubscribeParams {
+    device: Option<u32>, // all the devices if none
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))
+}
+
+fn result<T: Serialize>(value: T) -> Result<Value, RpcError> {
+    Ok(serde_json::to_value(value).expect("Failed to serialize RPC result"))
+}
+
+struct Subscription {
+    device: Option<u32>,
+    events: broadcast::Receiver<DeviceEvent>,
+}
+
+/// Client connected to the control socket
+struct RpcConnection {
+    ctrl: ControllerHandle,
+    role: Role,
+    uid: u32,
+    writer: OwnedWriteHalf,
+    subscription: Option<Subscription>,
+}
+
+impl RpcConnection {
+    async fn handle_line(&mut self, line: &str) -> io::Result<()> {
+        let request: RpcRequest = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(err) => {
+                let error = RpcError::new(PARSE_ERROR, err.to_string());
+                return self.respond(Value::Null, Err(error)).await;
+            }
+        };
+
+        let ret = if request.jsonrpc != "2.0" {
+            Err(RpcError::new(
+                INVALID_REQUEST,
+                "Unsupported JSON-RPC version",
+            ))
+        } else {
+            self.call(&request.method, request.params).await
+        };
+        match request.id {
+            Some(id) => self.respond(id, ret).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn call(&mut self, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "query" => {
+                let params: QueryParams = parse_params(params)?;
+                self.authorize(Role::Operator, params.id, "query").await?;
+                let id = self.ctrl.query(params.id, params.timeout).await;
+                result(QueryResponse { id })
+            }
+            "stats" => result(self.ctrl.get_stats().await.0),
+            "can_stats" => result(self.ctrl.get_stats().await.1),
+            "devices" => result(self.ctrl.get_devices().await),
+            "device" => {
+                let params: DeviceParams = parse_params(params)?;
+                let devices = self.ctrl.get_devices().await;
+                let device = devices.into_iter().find(|device| device.id == params.id);
+                result(device.ok_or(DeviceError::NotFound)?)
+            }
+            "actions" => {
+                let params: DeviceParams = parse_params(params)?;
+                let actions = self.ctrl.get_actions(params.id).await;
+                result(actions.ok_or(DeviceError::NotFound)?)
+            }
+            "action" => {
+                let params: ActionParams = parse_params(params)?;
+                let devices = self.ctrl.get_devices().await;
+                let device = devices.into_iter().find(|device| device.id == params.id);
+                let role =
+                    named_action_role(&device.ok_or(DeviceError::NotFound)?.node, &params.name);
+                self.authorize(role, params.id, &params.name).await?;
+                self.ctrl
+                    .named_action(
+                        params.id,
+                        params.name,
+                        params.args,
+                        Origin::ControlSocket { uid: self.uid },
+                    )
+                    .await?;
+                result(())
+            }
+            "subscribe" => {
+                let params: SubscribeParams = match params {
+                    Value::Null => SubscribeParams::default(),
+                    params => parse_params(params)?,
+                };
+                self.subscription = Some(Subscription {
+                    device: params.device,
+                    events: self.ctrl.subscribe().await,
+                });
+                result(true)
+            }
+            "unsubscribe" => result(self.subscription.take().is_some()),
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, "Method not found")),
+        }
+    }
+
+    /// Check the role of the peer, recording the denied calls in the audit log
+    async fn authorize(&self, role: Role, device_id: u32, action: &str) -> Result<(), RpcError> {
+        if self.role >= role {
+            return Ok(());
+        }
+        self.ctrl
+            .record_audit(AuditRecord {
+                timestamp: Utc::now(),
+                origin: Origin::ControlSocket { uid: self.uid },
+                device_id: Some(device_id),
+                action: action.to_string(),
+                args: Value::Null,
+                error: Some("Permission denied".to_string()),
+            })
+            .await;
+        Err(RpcError::new(PERMISSION_DENIED, "Permission denied"))
+    }
+
+    async fn respond(&mut self, id: Value, ret: Result<Value, RpcError>) -> io::Result<()> {
+        let (result, error) = match ret {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        let response = RpcResponse {
+            jsonrpc: "2.0",
+            id,
+            result,
+            error,
+        };
+        self.write(&response).await
+    }
+
+    async fn notify(&mut self, event: &DeviceEvent) -> io::Result<()> {
+        let notification = RpcNotification {
+            jsonrpc: "2.0",
+            method: "event",
+            params: event,
+        };
+        self.write(&notification).await
+    }
+
+    async fn write<T: Serialize>(&mut self, message: &T) -> io::Result<()> {
+        let mut line = serde_json::to_vec(message).expect("Failed to serialize RPC message");
+        line.push(b'\n');
+        self.writer.write_all(&line).await
+    }
+}
+
+/// Next event of the subscription, never ready without one
+async fn next_event(subscription: &mut Option<Subscription>) -> Option<DeviceEvent> {
+    let Some(subscription) = subscription else {
+        return std::future::pending().await;
+    };
+    loop {
+        match subscription.events.recv().await {
+            Ok(event) if subscription.device.is_some_and(|id| id != event.device_id) => {}
+            Ok(event) => return Some(event),
+            Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    ctrl: ControllerHandle,
+    role: Role,
+    uid: u32,
+    mut shutdown: Shutdown,
+) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    let mut conn = RpcConnection {
+        ctrl,
+        role,
+        uid,
+        writer,
+        subscription: None,
+    };
+
+    loop {
+        // the partial line is kept in `line` when another branch completes
+        let mut limited = (&mut reader).take((MAX_LINE_LEN + 1 - line.len()) as u64);
+        select! {
+            read = limited.read_until(b'\n', &mut line) => {
+                let eof = read? == 0;
+                if !eof && !line.ends_with(b"\n") && line.len() > MAX_LINE_LEN {
+                    let error = RpcError::new(INVALID_REQUEST, "Request too long");
+                    conn.respond(Value::Null, Err(error)).await?;
+                    break;
+                }
+                if eof || line.ends_with(b"\n") {
+                    let request = String::from_utf8_lossy(&line);
+                    if !request.trim().is_empty() {
+                        conn.handle_line(&request).await?;
+                    }
+                    line.clear();
+                }
+                if eof {
+                    break;
+                }
+            },
+            event = next_event(&mut conn.subscription) => match event {
+                Some(event) => conn.notify(&event).await?,
+                None => conn.subscription = None,
+            },
+            _ = shutdown.recv() => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Serve the line-delimited JSON-RPC 2.0 protocol on the control socket,
+/// until shutdown
+pub async fn run_rpc(config: RpcConfig, ctrl: ControllerHandle, mut shutdown: Shutdown) {
+    // left over by a previous run, unless still served or not a socket
+    if let Ok(metadata) = fs::symlink_metadata(&config.path) {
+        if !metadata.file_type().is_socket() {
+            println!(
+                "Control socket not started, {} is not a socket",
+                config.path.display()
+            );
+            return;
+        }
+        if UnixStream::connect(&config.path).await.is_ok() {
+            println!(
+                "Control socket not started, {} is in use",
+                config.path.display()
+            );
+            return;
+        }
+        let _ = fs::remove_file(&config.path);
+    }
+    let listener = match UnixListener::bind(&config.path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("Failed to bind {}: {}", config.path.display(), err);
+            return;
+        }
+    };
+    let owner = match fs::set_permissions(&config.path, fs::Permissions::from_mode(config.mode))
+        .and_then(|_| fs::metadata(&config.path))
+    {
+        Ok(metadata) => metadata.uid(), // the daemon user
+        Err(err) => {
+            println!("Failed to set up {}: {}", config.path.display(), err);
+            return;
+        }
+    };
+    println!("Control socket on {}", config.path.display());
+
+    // the connections are stopped along with the server
+    let (notify_shutdown, _) = broadcast::channel(1);
+    loop {
+        let stream = select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    println!("Control socket accept failed: {}", err);
+                    continue;
+                }
+            },
+            _ = shutdown.recv() => break,
+        };
+
+        let cred = match stream.peer_cred() {
+            Ok(cred) => cred,
+            Err(err) => {
+                println!("Control socket peer credentials unavailable: {}", err);
+                continue;
+            }
+        };
+        let role = config.peer_role(owner, cred.uid(), cred.gid());
+        println!(
+            "Control socket client uid {} gid {} pid {:?}: {:?}",
+            cred.uid(),
+            cred.gid(),
+            cred.pid(),
+            role
+        );
+
+        let uid = cred.uid();
+        let ctrl = ctrl.clone();
+        let shutdown = Shutdown::new(notify_shutdown.subscribe());
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(stream, ctrl, role, uid, shutdown).await {
+                println!("Control socket client error: {}", err);
+            }
+        });
+    }
+
+    let _ = notify_shutdown.send(());
+    let _ = fs::remove_file(&config.path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{runtime::Builder, sync::mpsc};
+
+    /// Send `requests` on a connection of a viewer and return the responses until closed
+    fn session(requests: Vec<u8>) -> Vec<Value> {
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        let (sender, _receiver) = mpsc::channel(8);
+        let ctrl = ControllerHandle::new(&rt, sender);
+        rt.block_on(async {
+            let (_notify, receiver) = broadcast::channel(1);
+            let (server, client) = UnixStream::pair().unwrap();
+            let shutdown = Shutdown::new(receiver);
+            let conn = tokio::spawn(handle_connection(
+                server,
+                ctrl,
+                Role::Viewer,
+                1000,
+                shutdown,
+            ));
+            let (mut reader, mut writer) = client.into_split();
+            // the server may close before reading everything
+            tokio::spawn(async move {
+                let _ = writer.write_all(&requests).await;
+                let _ = writer.shutdown().await;
+            });
+            let mut output = String::new();
+            reader.read_to_string(&mut output).await.unwrap();
+            conn.await.unwrap().unwrap();
+            output
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn peers_get_their_configured_role() {
+        let config = RpcConfig {
+            admin_uids: vec![1001],
+            admin_gids: vec![10],
+            operator_uids: vec![1002],
+            operator_gids: vec![20],
+            ..Default::default()
+        };
+        assert_eq!(config.peer_role(1000, 0, 0), Role::Admin);
+        assert_eq!(config.peer_role(1000, 1000, 100), Role::Admin);
+        assert_eq!(config.peer_role(1000, 1001, 100), Role::Admin);
+        assert_eq!(config.peer_role(1000, 1003, 10), Role::Admin);
+        assert_eq!(config.peer_role(1000, 1002, 100), Role::Operator);
+        assert_eq!(config.peer_role(1000, 1003, 20), Role::Operator);
+        assert_eq!(config.peer_role(1000, 1003, 100), Role::Viewer);
+    }
+
+    #[test]
+    fn requests_get_a_response_each() {
+        let requests = concat!(
+            "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"reboot\"}\n",
+            "\n",
+            "{\"jsonrpc\":\"2.0\",\"method\":\"unsubscribe\"}\n",
+            "{\"jsonrpc\":\"1.0\",\"id\":\"two\",\"method\":\"unsubscribe\"}\n",
+            "not json\n",
+            "{\"jsonrpc\":\"2.0\",\"id\":3,\"method\":\"unsubscribe\"}",
+        );
+        let responses = session(requests.into());
+        assert_eq!(
+            responses,
+            vec![
+                serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": 1,
+                    "error": {"code": METHOD_NOT_FOUND, "message": "Method not found"},
+                }),
+                serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": "two",
+                    "error": {"code": INVALID_REQUEST, "message": "Unsupported JSON-RPC version"},
+                }),
+                responses[2].clone(),
+                serde_json::json!({"jsonrpc": "2.0", "id": 3, "result": false}),
+            ]
+        );
+        assert_eq!(responses[2]["id"], Value::Null);
+        assert_eq!(responses[2]["error"]["code"], PARSE_ERROR);
+    }
+
+    #[test]
+    fn too_long_requests_close_the_connection() {
+        let mut requests = b"{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"unsubscribe\"}\n".to_vec();
+        requests.extend(vec![b' '; MAX_LINE_LEN + 1]);
+        requests.extend(b"{\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"unsubscribe\"}\n");
+        let responses = session(requests);
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0]["result"], false);
+        assert_eq!(responses[1]["error"]["code"], INVALID_REQUEST);
+        assert_eq!(responses[1]["error"]["message"], "Request too long");
+    }
+}
@@ -0,0 +1,662 @@
+use chrono::{DateTime, Datelike, Local, NaiveTime, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use thiserror::Error;
+
+use crate::{
+    device::{DeviceError, DeviceNodeTrait},
+    event::DeviceEvent,
+    registry::DeviceRegistry,
+};
+
+#[derive(Error, Debug)]
+pub enum RuleError {
+    #[error("Rule without a name")]
+    MissingName,
+    #[error("Duplicate rule: {0}")]
+    DuplicateName(String),
+    #[error("Rule {0} has no action")]
+    NoAction(String),
+    #[error("Rule {0}: action {1} needs either a device or a type")]
+    InvalidTarget(String, String),
+    #[error("Rule {0}: unknown device type {1}")]
+    UnknownType(String, String),
+    #[error("Rule {0}: condition on {1} needs equals, above or below")]
+    InvalidCondition(String, String),
+    #[error("Rule {0}: action {1} on device {2}: {3}")]
+    InvalidAction(String, String, u32, DeviceError),
+}
+
+/// Field of a device snapshot, compared to a value
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct StateCondition {
+    pub device: u32,
+    pub field: String, // as in the snapshot, e.g. "state" or "zones.0.temperature"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub equals: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub above: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub below: Option<f64>,
+}
+
+impl StateCondition {
+    fn is_valid(&self) -> bool {
+        self.equals.is_some() || self.above.is_some() || self.below.is_some()
+    }
+
+    fn matches(&self, snapshots: &[Value]) -> bool {
+        let pointer = format!("/{}", self.field.replace('.', "/"));
+        let Some(value) = snapshots
+            .iter()
+            .find(|snapshot| snapshot["id"] == self.device)
+            .and_then(|snapshot| snapshot.pointer(&pointer))
+        else {
+            return false;
+        };
+
+        self.equals.as_ref().is_none_or(|equals| value == equals)
+            && self
+                .above
+                .is_none_or(|above| value.as_f64().is_some_and(|v| v > above))
+            && self
+                .below
+                .is_none_or(|below| value.as_f64().is_some_and(|v| v < below))
+    }
+}
+
+/// What fires a rule
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Trigger {
+    /// Device event of the given kind, e.g. "alarm_triggered", its fields
+    /// matching the given ones
+    Event {
+        event: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device: Option<u32>, // any device if none
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        fields: BTreeMap<String, Value>,
+    },
+    /// Local time of day
+    Time {
+        at: NaiveTime,
+        #[cfg_attr(feature = "openapi", schema(value_type = Vec<String>))]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        days: Vec<Weekday>, // every day if empty
+    },
+    /// Device state becoming true
+    State(StateCondition),
+}
+
+/// Checked once a rule is triggered, all of them must hold
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    State(StateCondition),
+    /// Local time window, `from` included and `to` excluded, across midnight
+    /// if `to` is before `from`
+    Time {
+        from: NaiveTime,
+        to: NaiveTime,
+        #[cfg_attr(feature = "openapi", schema(value_type = Vec<String>))]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        days: Vec<Weekday>, // every day if empty
+    },
+}
+
+impl Condition {
+    fn matches(&self, now: &DateTime<Local>, snapshots: &[Value]) -> bool {
+        match self {
+            Condition::State(condition) => condition.matches(snapshots),
+            Condition::Time { from, to, days } => {
+                let time = now.time();
+                let in_window = if from <= to {
+                    *from <= time && time < *to
+                } else {
+                    *from <= time || time < *to
+                };
+                in_window && (days.is_empty() || days.contains(&now.weekday()))
+            }
+        }
+    }
+}
+
+/// Action run by name, as listed by the device actions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct RuleAction {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<u32>,
+    // every device of the type, e.g. "heater", rather than a single one
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub type_name: Option<String>,
+    pub action: String,
+    #[serde(default)]
+    pub args: Value,
+}
+
+impl RuleAction {
+    /// Ids of the target devices, among the snapshots
+    pub fn targets(&self, snapshots: &[Value]) -> Vec<u32> {
+        snapshots
+            .iter()
+            .filter(|snapshot| match (self.device, &self.type_name) {
+                (Some(device), _) => snapshot["id"] == device,
+                (None, Some(type_name)) => snapshot["type"] == type_name.as_str(),
+                (None, None) => false,
+            })
+            .filter_map(|snapshot| snapshot["id"].as_u64())
+            .map(|id| id as u32)
+            .collect()
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Rule {
+    #[serde(default)]
+    pub name: String, // taken from the path when set through the API
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub dry_run: bool, // only log the actions that would run
+    pub trigger: Trigger,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
+    pub actions: Vec<RuleAction>,
+}
+
+impl Rule {
+    /// Check the rule, its actions being parsed by the target devices among
+    /// the given ones
+    pub fn check(
+        &self,
+        registry: &DeviceRegistry,
+        devices: &[Box<dyn DeviceNodeTrait>],
+    ) -> Result<(), RuleError> {
+        if self.name.is_empty() {
+            return Err(RuleError::MissingName);
+        }
+        if self.actions.is_empty() {
+            return Err(RuleError::NoAction(self.name.clone()));
+        }
+
+        let conditions = self.conditions.iter().filter_map(|c| match c {
+            Condition::State(condition) => Some(condition),
+            Condition::Time { .. } => None,
+        });
+        let trigger = match &self.trigger {
+            Trigger::State(condition) => Some(condition),
+            _ => None,
+        };
+        if let Some(condition) = trigger
+            .into_iter()
+            .chain(conditions)
+            .find(|c| !c.is_valid())
+        {
+            return Err(RuleError::InvalidCondition(
+                self.name.clone(),
+                condition.field.clone(),
+            ));
+        }
+
+        let snapshots = snapshots(devices);
+        for action in &self.actions {
+            match (action.device, &action.type_name) {
+                (Some(_), None) => {}
+                (None, Some(type_name)) if registry.is_registered(type_name) => {}
+                (None, Some(type_name)) => {
+                    return Err(RuleError::UnknownType(self.name.clone(), type_name.clone()))
+                }
+                _ => {
+                    return Err(RuleError::InvalidTarget(
+                        self.name.clone(),
+                        action.action.clone(),
+                    ))
+                }
+            }
+
+            for id in action.targets(&snapshots) {
+                let Some(device) = devices.iter().find(|device| device.id() == id) else {
+                    continue;
+                };
+                if let Err(err) = device.parse_action(&action.action, action.args.clone()) {
+                    return Err(RuleError::InvalidAction(
+                        self.name.clone(),
+                        action.action.clone(),
+                        id,
+                        err,
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check a set of rules, see `Rule::check`
+pub fn check_rules(
+    rules: &[Rule],
+    registry: &DeviceRegistry,
+    devices: &[Box<dyn DeviceNodeTrait>],
+) -> Result<(), RuleError> {
+    let mut names = BTreeSet::new();
+    for rule in rules {
+        rule.check(registry, devices)?;
+        if !names.insert(&rule.name) {
+            return Err(RuleError::DuplicateName(rule.name.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Snapshots of the devices, as seen by the conditions
+pub fn snapshots(devices: &[Box<dyn DeviceNodeTrait>]) -> Vec<Value> {
+    devices
+        .iter()
+        .map(|device| serde_json::to_value(device.snapshot()).unwrap_or_default())
+        .collect()
+}
+
+/// A rule and its counters, as listed by the API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct RuleStatus {
+    #[serde(flatten)]
+    pub rule: Rule,
+    pub fired: u64,
+    pub last_fired: Option<DateTime<Utc>>,
+}
+
+/// Actions to run for a rule fired
+#[derive(Debug)]
+pub struct Firing {
+    pub rule: String,
+    pub dry_run: bool,
+    pub actions: Vec<RuleAction>,
+}
+
+#[derive(Debug)]
+struct RuleEntry {
+    status: RuleStatus,
+    state: Option<bool>, // of a state trigger, None until first evaluated
+}
+
+/// Rules of the config, changed at runtime by `set` and `remove`.
+///
+/// The changes are kept as overrides of the configured rules, by name (None
+/// for a removed one), to be saved and applied again on the next start.
+#[derive(Debug, Default)]
+pub struct RuleEngine {
+    entries: Vec<RuleEntry>,
+    overrides: BTreeMap<String, Option<Rule>>,
+    last_tick: Option<DateTime<Local>>,
+}
+
+impl RuleEngine {
+    pub fn new(configured: Vec<Rule>, overrides: BTreeMap<String, Option<Rule>>) -> RuleEngine {
+        let mut engine = RuleEngine::default();
+        for rule in configured {
+            engine.insert(rule);
+        }
+        for (name, rule) in &overrides {
+            match rule {
+                Some(rule) => engine.insert(rule.clone()),
+                None => {
+                    engine
+                        .entries
+                        .retain(|entry| &entry.status.rule.name != name);
+                }
+            }
+        }
+        engine.overrides = overrides;
+        engine
+    }
+
+    fn insert(&mut self, rule: Rule) {
+        let entry = RuleEntry {
+            status: RuleStatus {
+                rule,
+                fired: 0,
+                last_fired: None,
+            },
+            state: None,
+        };
+        match self
+            .entries
+            .iter_mut()
+            .find(|e| e.status.rule.name == entry.status.rule.name)
+        {
+            Some(existing) => *existing = entry,
+            None => self.entries.push(entry),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn rules(&self) -> Vec<Rule> {
+        self.entries.iter().map(|e| e.status.rule.clone()).collect()
+    }
+
+    pub fn statuses(&self) -> Vec<RuleStatus> {
+        self.entries.iter().map(|e| e.status.clone()).collect()
+    }
+
+    pub fn overrides(&self) -> &BTreeMap<String, Option<Rule>> {
+        &self.overrides
+    }
+
+    /// Add or replace a rule, by name
+    pub fn set(&mut self, rule: Rule) {
+        self.overrides.insert(rule.name.clone(), Some(rule.clone()));
+        self.insert(rule);
+    }
+
+    /// Remove a rule, false if unknown
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|e| e.status.rule.name != name);
+        if self.entries.len() == before {
+            return false;
+        }
+        self.overrides.insert(name.to_string(), None);
+        true
+    }
+
+    /// Rules triggered by a device event
+    pub fn on_event(&mut self, event: &DeviceEvent, snapshots: &[Value]) -> Vec<Firing> {
+        let fields = serde_json::to_value(event).unwrap_or_default();
+        self.fire(&Local::now(), snapshots, |trigger, _| match trigger {
+            Trigger::Event {
+                event: kind,
+                device,
+                fields: expected,
+            } => {
+                event.kind.name() == kind
+                    && device.is_none_or(|device| event.device_id == device)
+                    && expected
+                        .iter()
+                        .all(|(name, value)| fields.get(name) == Some(value))
+            }
+            _ => false,
+        })
+    }
+
+    /// Rules triggered by a device state becoming true
+    pub fn on_state(&mut self, snapshots: &[Value]) -> Vec<Firing> {
+        self.fire(&Local::now(), snapshots, |trigger, state| match trigger {
+            Trigger::State(condition) => {
+                let matches = condition.matches(snapshots);
+                let previous = state.replace(matches);
+                matches && previous == Some(false)
+            }
+            _ => false,
+        })
+    }
+
+    /// Rules triggered by the time of day, since the previous tick
+    pub fn on_tick(&mut self, now: DateTime<Local>, snapshots: &[Value]) -> Vec<Firing> {
+        let Some(last) = self.last_tick.replace(now) else {
+            return Vec::new();
+        };
+        let (last, current) = (last.naive_local(), now.naive_local());
+        // the day of the previous tick as well, when crossing midnight
+        let mut dates = vec![last.date(), current.date()];
+        dates.dedup();
+        self.fire(&now, snapshots, |trigger, _| match trigger {
+            Trigger::Time { at, days } => dates.iter().any(|date| {
+                let at = date.and_time(*at);
+                last < at && at <= current && (days.is_empty() || days.contains(&date.weekday()))
+            }),
+            _ => false,
+        })
+    }
+
+    fn fire<F>(
+        &mut self,
+        now: &DateTime<Local>,
+        snapshots: &[Value],
+        mut triggered: F,
+    ) -> Vec<Firing>
+    where
+        F: FnMut(&Trigger, &mut Option<bool>) -> bool,
+    {
+        let mut firings = Vec::new();
+        for entry in &mut self.entries {
+            let rule = &entry.status.rule;
+            // state triggers are followed even when disabled, not to fire
+            // on a state already true once enabled
+            if !triggered(&rule.trigger, &mut entry.state) || !rule.enabled {
+                continue;
+            }
+            if !rule.conditions.iter().all(|c| c.matches(now, snapshots)) {
+                continue;
+            }
+
+            entry.status.fired += 1;
+            entry.status.last_fired = Some(Utc::now());
+            firings.push(Firing {
+                rule: rule.name.clone(),
+                dry_run: rule.dry_run,
+                actions: rule.actions.clone(),
+            });
+        }
+        firings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::DeviceEventKind;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn rule(value: Value) -> Rule {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn time_rule(name: &str, at: &str, days: &[&str]) -> Rule {
+        rule(json!({
+            "name": name,
+            "trigger": { "type": "time", "at": at, "days": days },
+            "actions": [{ "device": 1, "action": "power_lights" }],
+        }))
+    }
+
+    fn state_rule(enabled: bool) -> Rule {
+        rule(json!({
+            "name": "cold",
+            "enabled": enabled,
+            "trigger": { "type": "state", "device": 2, "field": "zones.0.temperature", "below": 5.0 },
+            "actions": [{ "device": 2, "action": "heater_power" }],
+        }))
+    }
+
+    fn heater(temperature: f64) -> Vec<Value> {
+        vec![json!({ "id": 2, "type": "heater", "zones": [{ "temperature": temperature }] })]
+    }
+
+    // 2024-01-01 is a Monday
+    fn at(day: u32, h: u32, m: u32, s: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 1, day, h, m, s).unwrap()
+    }
+
+    fn names(firings: &[Firing]) -> Vec<&str> {
+        firings.iter().map(|firing| firing.rule.as_str()).collect()
+    }
+
+    #[test]
+    fn time_condition_across_midnight() {
+        let condition: Condition = serde_json::from_value(json!({
+            "type": "time", "from": "22:00:00", "to": "06:00:00"
+        }))
+        .unwrap();
+        assert!(condition.matches(&at(1, 23, 0, 0), &[]));
+        assert!(condition.matches(&at(2, 5, 59, 59), &[]));
+        assert!(!condition.matches(&at(2, 6, 0, 0), &[]));
+        assert!(!condition.matches(&at(1, 21, 59, 59), &[]));
+
+        let condition: Condition = serde_json::from_value(json!({
+            "type": "time", "from": "08:00:00", "to": "18:00:00", "days": ["Mon"]
+        }))
+        .unwrap();
+        assert!(condition.matches(&at(1, 12, 0, 0), &[]));
+        assert!(!condition.matches(&at(2, 12, 0, 0), &[]));
+    }
+
+    #[test]
+    fn time_trigger_fires_once_when_crossed() {
+        let mut engine =
+            RuleEngine::new(vec![time_rule("morning", "07:00:00", &[])], BTreeMap::new());
+
+        // the first tick only sets the starting point
+        assert!(engine.on_tick(at(1, 7, 0, 0), &[]).is_empty());
+        assert!(engine.on_tick(at(1, 7, 0, 2), &[]).is_empty());
+        assert!(engine.on_tick(at(2, 6, 59, 59), &[]).is_empty());
+        assert_eq!(names(&engine.on_tick(at(2, 7, 0, 1), &[])), ["morning"]);
+        assert!(engine.on_tick(at(2, 7, 0, 3), &[]).is_empty());
+        assert!(engine.on_tick(at(3, 6, 59, 59), &[]).is_empty());
+        assert_eq!(names(&engine.on_tick(at(3, 7, 0, 0), &[])), ["morning"]);
+        assert_eq!(engine.statuses()[0].fired, 2);
+    }
+
+    #[test]
+    fn time_trigger_at_day_boundaries() {
+        let rules = vec![
+            time_rule("late", "23:59:59", &["Mon"]),
+            time_rule("midnight", "00:00:00", &["Tue"]),
+            time_rule("monday-midnight", "00:00:00", &["Mon"]),
+        ];
+        let mut engine = RuleEngine::new(rules, BTreeMap::new());
+
+        engine.on_tick(at(1, 23, 59, 58), &[]);
+        assert_eq!(
+            names(&engine.on_tick(at(2, 0, 0, 1), &[])),
+            ["late", "midnight"]
+        );
+        assert!(engine.on_tick(at(2, 0, 0, 3), &[]).is_empty());
+    }
+
+    #[test]
+    fn state_trigger_fires_on_edges() {
+        let mut engine = RuleEngine::new(vec![state_rule(true)], BTreeMap::new());
+
+        // already true when first evaluated
+        assert!(engine.on_state(&heater(4.0)).is_empty());
+        assert!(engine.on_state(&heater(6.0)).is_empty());
+        assert_eq!(names(&engine.on_state(&heater(4.0))), ["cold"]);
+        assert!(engine.on_state(&heater(3.0)).is_empty());
+        assert!(engine.on_state(&heater(6.0)).is_empty());
+        assert_eq!(names(&engine.on_state(&heater(4.0))), ["cold"]);
+    }
+
+    #[test]
+    fn state_trigger_while_disabled() {
+        let mut engine = RuleEngine::new(vec![state_rule(true)], BTreeMap::new());
+        engine.on_state(&heater(6.0));
+
+        engine.set(state_rule(false));
+        assert!(engine.on_state(&heater(6.0)).is_empty());
+        assert!(engine.on_state(&heater(4.0)).is_empty());
+
+        // enabled while the state holds, only its next edge fires
+        engine.set(state_rule(true));
+        assert!(engine.on_state(&heater(4.0)).is_empty());
+        assert!(engine.on_state(&heater(6.0)).is_empty());
+        assert_eq!(names(&engine.on_state(&heater(4.0))), ["cold"]);
+    }
+
+    #[test]
+    fn event_trigger_matches_fields() {
+        let lights = rule(json!({
+            "name": "lights",
+            "trigger": { "type": "event", "event": "alarm_lights_changed", "device": 1, "fields": { "front": true } },
+            "actions": [{ "device": 2, "action": "set_active", "args": { "active": true } }],
+        }));
+        let mut engine = RuleEngine::new(vec![lights], BTreeMap::new());
+
+        let event = |device_id, front| {
+            DeviceEvent::new(
+                device_id,
+                DeviceEventKind::AlarmLightsChanged { front, rear: false },
+            )
+        };
+        assert_eq!(names(&engine.on_event(&event(1, true), &[])), ["lights"]);
+        assert!(engine.on_event(&event(1, false), &[]).is_empty());
+        assert!(engine.on_event(&event(3, true), &[]).is_empty());
+        assert!(engine
+            .on_event(&DeviceEvent::new(1, DeviceEventKind::AlarmLockedOut), &[])
+            .is_empty());
+    }
+
+    #[test]
+    fn conditions_gate_firings() {
+        let mut rule = time_rule("armed", "07:00:00", &[]);
+        rule.conditions = vec![serde_json::from_value(json!({
+            "type": "state", "device": 1, "field": "state", "equals": "armed_away"
+        }))
+        .unwrap()];
+        rule.dry_run = true;
+        let mut engine = RuleEngine::new(vec![rule], BTreeMap::new());
+
+        let alarm = |state| vec![json!({ "id": 1, "type": "alarm", "state": state })];
+        engine.on_tick(at(1, 6, 59, 59), &alarm("disarmed"));
+        assert!(engine
+            .on_tick(at(1, 7, 0, 1), &alarm("disarmed"))
+            .is_empty());
+        engine.on_tick(at(2, 6, 59, 59), &alarm("armed_away"));
+        let firings = engine.on_tick(at(2, 7, 0, 1), &alarm("armed_away"));
+        assert_eq!(names(&firings), ["armed"]);
+        assert!(firings[0].dry_run);
+    }
+
+    #[test]
+    fn overrides_survive_a_restart() {
+        let configured = vec![
+            time_rule("morning", "07:00:00", &[]),
+            time_rule("evening", "19:00:00", &[]),
+        ];
+        let mut engine = RuleEngine::new(configured.clone(), BTreeMap::new());
+
+        engine.set(time_rule("morning", "06:30:00", &[]));
+        engine.set(time_rule("night", "23:00:00", &[]));
+        assert!(engine.remove("evening"));
+        assert!(!engine.remove("unknown"));
+
+        // saved in the state file as JSON, then applied over the config again
+        let saved = serde_json::to_value(engine.overrides()).unwrap();
+        let restored = RuleEngine::new(configured, serde_json::from_value(saved).unwrap());
+
+        let rules: Vec<(String, NaiveTime)> = restored
+            .rules()
+            .into_iter()
+            .map(|rule| match rule.trigger {
+                Trigger::Time { at, .. } => (rule.name, at),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            rules,
+            [
+                ("morning".to_string(), "06:30:00".parse().unwrap()),
+                ("night".to_string(), "23:00:00".parse().unwrap()),
+            ]
+        );
+        assert_eq!(restored.overrides().len(), 3);
+        assert!(restored.overrides()["evening"].is_none());
+    }
+}
@@ -0,0 +1,142 @@
+use chrono::{DateTime, Datelike, Local, NaiveTime, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+
+use crate::heater::{HeaterState, ZONE_COUNT};
+
+/// Time slot of a weekly program, `start` included and `end` excluded. A slot
+/// ending before it starts runs past midnight, into the day after each of
+/// its `days`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ScheduleSlot {
+    #[cfg_attr(feature = "openapi", schema(value_type = Vec<String>))]
+    pub days: Vec<Weekday>, // "Mon", "Tue", ...
+    pub start: NaiveTime, // local time, "06:00:00"
+    pub end: NaiveTime,
+    pub state: HeaterState,
+}
+
+impl ScheduleSlot {
+    fn matches(&self, now: &DateTime<Local>) -> bool {
+        let time = now.time();
+        let today = self.days.contains(&now.weekday());
+        if self.start <= self.end {
+            today && self.start <= time && time < self.end
+        } else {
+            let yesterday = self.days.contains(&now.weekday().pred());
+            (today && self.start <= time) || (yesterday && time < self.end)
+        }
+    }
+}
+
+/// State forced until a given time, whatever the program says
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ScheduleOverride {
+    pub state: HeaterState,
+    pub until: Option<DateTime<Utc>>, // None: until removed
+}
+
+impl ScheduleOverride {
+    fn is_active(&self, now: &DateTime<Local>) -> bool {
+        self.until
+            .is_none_or(|until| now.with_timezone(&Utc) < until)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ZoneProgram {
+    pub default: HeaterState, // outside of the slots
+    #[serde(default)]
+    pub slots: Vec<ScheduleSlot>,
+    #[serde(rename = "override", default)]
+    pub override_: Option<ScheduleOverride>,
+}
+
+impl Default for ZoneProgram {
+    fn default() -> ZoneProgram {
+        ZoneProgram {
+            default: HeaterState::Eco,
+            slots: Vec::new(),
+            override_: None,
+        }
+    }
+}
+
+/// Weekly program of the zones of a heater node
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct HeaterSchedule {
+    pub zones: [ZoneProgram; ZONE_COUNT],
+    #[serde(default)]
+    pub away: Option<ScheduleOverride>, // holiday mode, applies to all zones
+}
+
+impl HeaterSchedule {
+    /// State of the zone at the given time: away mode first, then the zone
+    /// override, the first matching slot and finally the zone default state.
+    pub fn state_at(&self, zone: usize, now: &DateTime<Local>) -> HeaterState {
+        if let Some(away) = self.away.as_ref().filter(|away| away.is_active(now)) {
+            return away.state;
+        }
+
+        let program = &self.zones[zone];
+        if let Some(o) = program.override_.as_ref().filter(|o| o.is_active(now)) {
+            return o.state;
+        }
+
+        program
+            .slots
+            .iter()
+            .find(|slot| slot.matches(now))
+            .map_or(program.default, |slot| slot.state)
+    }
+
+    pub fn states_at(&self, now: &DateTime<Local>) -> [HeaterState; ZONE_COUNT] {
+        std::array::from_fn(|zone| self.state_at(zone, now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn slot(days: &[Weekday], start: &str, end: &str) -> ScheduleSlot {
+        ScheduleSlot {
+            days: days.to_vec(),
+            start: start.parse().unwrap(),
+            end: end.parse().unwrap(),
+            state: HeaterState::Comfort,
+        }
+    }
+
+    // 2024-01-05 is a Friday
+    fn at(day: u32, h: u32, m: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 1, day, h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn slot_within_a_day() {
+        let slot = slot(&[Weekday::Fri], "06:00:00", "08:00:00");
+        assert!(!slot.matches(&at(5, 5, 59)));
+        assert!(slot.matches(&at(5, 6, 0)));
+        assert!(!slot.matches(&at(5, 8, 0)));
+        assert!(!slot.matches(&at(6, 7, 0)));
+    }
+
+    #[test]
+    fn slot_past_midnight() {
+        let slot = slot(&[Weekday::Fri], "22:00:00", "06:00:00");
+        assert!(!slot.matches(&at(5, 21, 59)));
+        assert!(slot.matches(&at(5, 22, 0)));
+        assert!(slot.matches(&at(5, 23, 59)));
+        assert!(slot.matches(&at(6, 0, 0)));
+        assert!(slot.matches(&at(6, 5, 59)));
+        assert!(!slot.matches(&at(6, 6, 0)));
+        assert!(!slot.matches(&at(6, 22, 0)));
+        // the morning of the start day belongs to the day before
+        assert!(!slot.matches(&at(5, 3, 0)));
+    }
+}
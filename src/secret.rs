@@ -0,0 +1,29 @@
+use sha2::{Digest, Sha256};
+
+/// Hash a secret (user code, token...) the way it is stored in the config:
+/// `<salt>$<hex encoded sha256(salt + secret)>`
+pub fn hash_secret(salt: &str, secret: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(salt.as_bytes())
+        .chain_update(secret.as_bytes())
+        .finalize();
+
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}${}", salt, hex)
+}
+
+/// Check a secret against a stored hash, malformed hashes never match
+pub fn verify_secret(hash: &str, secret: &str) -> bool {
+    let Some((salt, _)) = hash.split_once('$') else {
+        return false;
+    };
+
+    // compare in constant time, not to leak how much of the hash matched
+    let computed = hash_secret(salt, secret);
+    computed.len() == hash.len()
+        && computed
+            .bytes()
+            .zip(hash.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
@@ -0,0 +1,143 @@
+use std::{
+    ffi::CString,
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+use tokio::io::unix::AsyncFd;
+
+use crate::can::CanFrame;
+
+/// Raw SocketCAN socket, bound to a single interface
+#[derive(Debug)]
+pub struct CanSocket {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl CanSocket {
+    pub fn open(iface: &str) -> io::Result<CanSocket> {
+        let name = CString::new(iface)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid interface name"))?;
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let fd = unsafe {
+            libc::socket(
+                libc::PF_CAN,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::CAN_RAW,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_can = unsafe { mem::zeroed() };
+        addr.can_family = libc::AF_CAN as libc::sa_family_t;
+        addr.can_ifindex = index as libc::c_int;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_can as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_can>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(CanSocket {
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+
+    pub async fn send(&self, frame: &CanFrame) -> io::Result<()> {
+        let mut raw: libc::can_frame = unsafe { mem::zeroed() };
+        raw.can_id = if frame.id > libc::CAN_SFF_MASK {
+            (frame.id & libc::CAN_EFF_MASK) | libc::CAN_EFF_FLAG
+        } else {
+            frame.id
+        };
+        raw.can_dlc = frame.data.len() as u8;
+        raw.data = frame.data;
+
+        loop {
+            let mut guard = self.fd.writable().await?;
+            match guard.try_io(|fd| write_frame(fd.get_ref(), &raw)) {
+                Ok(ret) => return ret,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Next frame, waiting for it
+    pub async fn recv(&self) -> io::Result<CanFrame> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            match guard.try_io(|fd| read_frame(fd.get_ref())) {
+                Ok(ret) => return ret,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Next frame if one is already received
+    pub fn try_recv(&self) -> io::Result<Option<CanFrame>> {
+        match read_frame(self.fd.get_ref()) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+fn write_frame(fd: &OwnedFd, raw: &libc::can_frame) -> io::Result<()> {
+    let size = mem::size_of::<libc::can_frame>();
+    let ret = unsafe {
+        libc::write(
+            fd.as_raw_fd(),
+            raw as *const libc::can_frame as *const libc::c_void,
+            size,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn read_frame(fd: &OwnedFd) -> io::Result<CanFrame> {
+    let mut raw: libc::can_frame = unsafe { mem::zeroed() };
+    let size = mem::size_of::<libc::can_frame>();
+    let ret = unsafe {
+        libc::read(
+            fd.as_raw_fd(),
+            &mut raw as *mut libc::can_frame as *mut libc::c_void,
+            size,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if ret as usize != size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Truncated CAN frame",
+        ));
+    }
+
+    // shorter frames are padded with zeros
+    let mut data = [0; 8];
+    let len = (raw.can_dlc as usize).min(data.len());
+    data[..len].copy_from_slice(&raw.data[..len]);
+
+    let id = if raw.can_id & libc::CAN_EFF_FLAG != 0 {
+        raw.can_id & libc::CAN_EFF_MASK
+    } else {
+        raw.can_id & libc::CAN_SFF_MASK
+    };
+
+    Ok(CanFrame { id, data })
+}
@@ -0,0 +1,490 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use crate::event::DeviceEvent;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Serialization error: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub path: Option<PathBuf>, // None keeps the history in memory only
+    pub retention_days: u32,
+    pub max_events: usize,
+    pub record_frames: bool,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> HistoryConfig {
+        HistoryConfig {
+            path: Some(PathBuf::from("history.jsonl")),
+            retention_days: 30,
+            max_events: 100_000,
+            record_frames: false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct HistoryQuery {
+    pub device_id: u32,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub kind: Option<String>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct HistoryPage {
+    pub total: usize,
+    pub offset: usize,
+    pub events: Vec<DeviceEvent>,
+}
+
+/// Append-only event log, kept in memory and mirrored to a JSON lines file.
+///
+/// Events older than the retention period (or exceeding `max_events`) are
+/// dropped on `prune()`, the file is then rewritten with the remaining events.
+#[derive(Debug)]
+pub struct HistoryStore {
+    config: HistoryConfig,
+    events: VecDeque<DeviceEvent>,
+    file: Option<File>,
+}
+
+impl HistoryStore {
+    pub fn open(config: HistoryConfig) -> Result<HistoryStore, StorageError> {
+        // without the storage feature, the history is only kept in memory
+        #[cfg(not(feature = "storage"))]
+        let config = HistoryConfig {
+            path: None,
+            ..config
+        };
+
+        let mut events = VecDeque::new();
+        let mut corrupted = 0;
+
+        if let Some(path) = &config.path {
+            if path.exists() {
+                let reader = BufReader::new(File::open(path)?);
+                for line in reader.lines() {
+                    let line = line?;
+                    // skip truncated or corrupted lines rather than failing
+                    match serde_json::from_str(&line) {
+                        Ok(event) => events.push_back(event),
+                        Err(_) => corrupted += 1,
+                    }
+                }
+            }
+        }
+
+        let file = match &config.path {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+
+        let mut store = HistoryStore {
+            config,
+            events,
+            file,
+        };
+        // drop the corrupted lines, the next event would be appended to a truncated one
+        if corrupted > 0 {
+            println!("Dropping {} corrupted history lines", corrupted);
+            store.rewrite()?;
+        }
+        store.prune()?;
+
+        Ok(store)
+    }
+
+    pub fn config(&self) -> &HistoryConfig {
+        &self.config
+    }
+
+    pub fn append(&mut self, event: DeviceEvent) -> Result<(), StorageError> {
+        if let Some(file) = &mut self.file {
+            let mut line = serde_json::to_vec(&event)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+        }
+
+        self.events.push_back(event);
+
+        Ok(())
+    }
+
+    /// Apply the retention policy
+    pub fn prune(&mut self) -> Result<(), StorageError> {
+        let before = self.events.len();
+        let limit = Utc::now() - Duration::days(self.config.retention_days as i64);
+
+        while let Some(event) = self.events.front() {
+            if event.timestamp < limit || self.events.len() > self.config.max_events {
+                self.events.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if self.events.len() != before {
+            self.rewrite()?;
+        }
+
+        Ok(())
+    }
+
+    fn rewrite(&mut self) -> Result<(), StorageError> {
+        let Some(path) = &self.config.path else {
+            return Ok(());
+        };
+
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            for event in &self.events {
+                let mut line = serde_json::to_vec(event)?;
+                line.push(b'\n');
+                file.write_all(&line)?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+
+        self.file = Some(OpenOptions::new().append(true).open(path)?);
+
+        Ok(())
+    }
+
+    pub fn query(&self, query: &HistoryQuery) -> HistoryPage {
+        let matching: Vec<&DeviceEvent> = self
+            .events
+            .iter()
+            .filter(|e| e.device_id == query.device_id)
+            .filter(|e| query.from.is_none_or(|from| e.timestamp >= from))
+            .filter(|e| query.to.is_none_or(|to| e.timestamp < to))
+            .filter(|e| query.kind.as_deref().is_none_or(|k| e.kind.name() == k))
+            .collect();
+
+        HistoryPage {
+            total: matching.len(),
+            offset: query.offset,
+            events: matching
+                .into_iter()
+                .skip(query.offset)
+                .take(query.limit)
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Persistent key/value store for the state changed at runtime (schedules...),
+/// values are kept as JSON and the whole store is rewritten on every change.
+#[derive(Debug, Default)]
+pub struct StateStore {
+    path: Option<PathBuf>, // None keeps the state in memory only
+    values: BTreeMap<String, serde_json::Value>,
+}
+
+impl StateStore {
+    pub fn open(path: Option<PathBuf>) -> Result<StateStore, StorageError> {
+        // without the storage feature, the state is only kept in memory
+        #[cfg(not(feature = "storage"))]
+        let path: Option<PathBuf> = path.and(None);
+
+        let values = match &path {
+            Some(path) if path.exists() => {
+                serde_json::from_reader(BufReader::new(File::open(path)?))?
+            }
+            _ => BTreeMap::new(),
+        };
+
+        Ok(StateStore { path, values })
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let value = self.values.get(key)?;
+        match serde_json::from_value(value.clone()) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                println!("Ignoring invalid state for {}: {}", key, err);
+                None
+            }
+        }
+    }
+
+    pub fn set<T: Serialize>(&mut self, key: &str, value: &T) -> Result<(), StorageError> {
+        self.values
+            .insert(key.to_string(), serde_json::to_value(value)?);
+        self.save()
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<(), StorageError> {
+        if self.values.remove(key).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), StorageError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        write_atomic(path, &serde_json::to_vec_pretty(&self.values)?)
+    }
+}
+
+/// Write a file through a temporary one, not to leave a truncated file behind
+fn write_atomic(path: &Path, content: &[u8]) -> Result<(), StorageError> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::DeviceEventKind;
+    #[cfg(feature = "storage")]
+    use tempfile::TempDir;
+
+    fn event(device_id: u32, age: Duration, kind: DeviceEventKind) -> DeviceEvent {
+        DeviceEvent {
+            timestamp: Utc::now() - age,
+            device_id,
+            kind,
+        }
+    }
+
+    #[cfg(feature = "storage")]
+    fn history(dir: &TempDir) -> HistoryConfig {
+        HistoryConfig {
+            path: Some(dir.path().join("history.jsonl")),
+            ..Default::default()
+        }
+    }
+
+    fn timestamps(store: &HistoryStore, device_id: u32) -> Vec<DateTime<Utc>> {
+        let query = HistoryQuery {
+            device_id,
+            limit: usize::MAX,
+            ..Default::default()
+        };
+        store
+            .query(&query)
+            .events
+            .iter()
+            .map(|e| e.timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn queries_filter_and_page_the_events() {
+        let mut store = HistoryStore::open(HistoryConfig {
+            path: None,
+            ..Default::default()
+        })
+        .unwrap();
+        for minutes in [50, 40, 30, 20, 10] {
+            let age = Duration::minutes(minutes);
+            store
+                .append(event(1, age, DeviceEventKind::Online))
+                .unwrap();
+            store
+                .append(event(2, age, DeviceEventKind::Offline))
+                .unwrap();
+        }
+        store
+            .append(event(1, Duration::minutes(5), DeviceEventKind::Offline))
+            .unwrap();
+
+        let page = store.query(&HistoryQuery {
+            device_id: 1,
+            limit: 10,
+            ..Default::default()
+        });
+        assert_eq!(page.total, 6);
+        assert!(page.events.iter().all(|e| e.device_id == 1));
+
+        let page = store.query(&HistoryQuery {
+            device_id: 1,
+            kind: Some("online".to_string()),
+            offset: 1,
+            limit: 2,
+            ..Default::default()
+        });
+        assert_eq!((page.total, page.offset, page.events.len()), (5, 1, 2));
+        assert_eq!(page.events[0].timestamp, timestamps(&store, 1)[1]);
+
+        // from is inclusive, to exclusive
+        let all = timestamps(&store, 2);
+        let page = store.query(&HistoryQuery {
+            device_id: 2,
+            from: Some(all[1]),
+            to: Some(all[3]),
+            limit: 10,
+            ..Default::default()
+        });
+        let found: Vec<_> = page.events.iter().map(|e| e.timestamp).collect();
+        assert_eq!(found, all[1..3]);
+    }
+
+    #[cfg(feature = "storage")]
+    #[test]
+    fn history_is_reloaded_from_the_file() {
+        let dir = TempDir::new().unwrap();
+        let mut store = HistoryStore::open(history(&dir)).unwrap();
+        store
+            .append(event(1, Duration::minutes(2), DeviceEventKind::Online))
+            .unwrap();
+        store
+            .append(event(1, Duration::minutes(1), DeviceEventKind::AlarmDuress))
+            .unwrap();
+        let before = timestamps(&store, 1);
+        drop(store);
+
+        let store = HistoryStore::open(history(&dir)).unwrap();
+        assert_eq!(timestamps(&store, 1), before);
+    }
+
+    #[cfg(feature = "storage")]
+    #[test]
+    fn old_events_are_pruned_from_the_file() {
+        let dir = TempDir::new().unwrap();
+        let config = HistoryConfig {
+            retention_days: 1,
+            ..history(&dir)
+        };
+        let mut store = HistoryStore::open(config.clone()).unwrap();
+        let day = Duration::days(1);
+        for age in [
+            day + Duration::minutes(1),
+            day - Duration::minutes(1),
+            Duration::zero(),
+        ] {
+            store
+                .append(event(1, age, DeviceEventKind::Online))
+                .unwrap();
+        }
+        let all = timestamps(&store, 1);
+
+        // the event just within the retention period is kept
+        store.prune().unwrap();
+        assert_eq!(timestamps(&store, 1), all[1..]);
+        drop(store);
+
+        let store = HistoryStore::open(config).unwrap();
+        assert_eq!(timestamps(&store, 1), all[1..]);
+        assert!(!dir.path().join("history.tmp").exists());
+    }
+
+    #[cfg(feature = "storage")]
+    #[test]
+    fn extra_events_are_pruned_from_the_file() {
+        let dir = TempDir::new().unwrap();
+        let config = HistoryConfig {
+            max_events: 2,
+            ..history(&dir)
+        };
+        let mut store = HistoryStore::open(config.clone()).unwrap();
+        for minutes in [3, 2, 1] {
+            let age = Duration::minutes(minutes);
+            store
+                .append(event(1, age, DeviceEventKind::Online))
+                .unwrap();
+        }
+        let all = timestamps(&store, 1);
+        drop(store);
+
+        // pruned when reopened
+        let store = HistoryStore::open(config).unwrap();
+        assert_eq!(timestamps(&store, 1), all[1..]);
+        let content = fs::read_to_string(dir.path().join("history.jsonl")).unwrap();
+        assert_eq!(content.lines().count(), 2);
+    }
+
+    #[cfg(feature = "storage")]
+    #[test]
+    fn truncated_lines_are_dropped() {
+        let dir = TempDir::new().unwrap();
+        let mut store = HistoryStore::open(history(&dir)).unwrap();
+        store
+            .append(event(1, Duration::minutes(2), DeviceEventKind::Online))
+            .unwrap();
+        store
+            .append(event(1, Duration::minutes(1), DeviceEventKind::Offline))
+            .unwrap();
+        let before = timestamps(&store, 1);
+        drop(store);
+
+        // cut the last line in the middle
+        let path = dir.path().join("history.jsonl");
+        let content = fs::read(&path).unwrap();
+        fs::write(&path, &content[..content.len() - 10]).unwrap();
+
+        let mut store = HistoryStore::open(history(&dir)).unwrap();
+        assert_eq!(timestamps(&store, 1), before[..1]);
+        store
+            .append(event(1, Duration::zero(), DeviceEventKind::Online))
+            .unwrap();
+        drop(store);
+
+        let store = HistoryStore::open(history(&dir)).unwrap();
+        assert_eq!(timestamps(&store, 1).len(), 2);
+        assert_eq!(timestamps(&store, 1)[0], before[0]);
+    }
+
+    #[cfg(feature = "storage")]
+    #[test]
+    fn state_is_saved_atomically() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state.json");
+        let mut store = StateStore::open(Some(path.clone())).unwrap();
+        store.set("schedule", &vec![1, 2, 3]).unwrap();
+        store.set("mode", &"away").unwrap();
+        store.remove("mode").unwrap();
+        store.remove("unknown").unwrap();
+        assert!(!dir.path().join("state.tmp").exists());
+
+        let store = StateStore::open(Some(path)).unwrap();
+        assert_eq!(store.get::<Vec<u32>>("schedule"), Some(vec![1, 2, 3]));
+        assert_eq!(store.get::<String>("mode"), None);
+        // a value of another type is ignored
+        assert_eq!(store.get::<String>("schedule"), None);
+    }
+
+    #[cfg(feature = "storage")]
+    #[test]
+    fn write_atomic_replaces_the_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file.json");
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+    }
+}
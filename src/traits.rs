@@ -1,3 +0,0 @@
-use crate::{alarm::AlarmAction, device::DeviceActionTrait};
-
-// pub struct DeviceNodeAction(Box<dyn DeviceActionTrait>);
\ No newline at end of file
@@ -1,63 +1,1198 @@
-use rocket::serde::{json::Json, Serialize};
-use rocket::{log::LogLevel, Build, Config, Rocket, State};
+use chrono::{DateTime, Utc};
+use rocket::config::Shutdown as ShutdownConfig;
+#[cfg(feature = "tls")]
+use rocket::config::{MutualTls, TlsConfig as RocketTlsConfig};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Header, Status};
+#[cfg(feature = "tls")]
+use rocket::mtls::Certificate;
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::status::BadRequest;
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::Json;
+use rocket::tokio::{
+    pin, select,
+    signal::unix::{signal, SignalKind},
+    sync::broadcast::error::RecvError,
+};
+use rocket::{log::LogLevel, Build, Config, Request, Response, Rocket, Shutdown, State};
+#[cfg(feature = "tls")]
+use rustls_pemfile::Item;
+use serde_json::Value;
+use std::collections::HashSet;
+#[cfg(feature = "tls")]
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+};
 
-use crate::alarm::{AlarmAction, AlarmNode};
+use crate::action::{ActionSpec, DeviceNodeAction};
+use crate::alarm::AlarmAction;
+use crate::api::QueryResponse;
+use crate::audit::{AuditPage, AuditQuery, AuditRecord, Origin};
+use crate::auth::{named_action_role, AuthConfig, AuthError, Identity, Role};
 use crate::can::CanStats;
-use crate::controller::{ControllerStats, DeviceNodeAction};
+#[cfg(feature = "tls")]
+use crate::config::TlsConfig;
+use crate::config::WebConfig;
+use crate::controller::ControllerStats;
+use crate::dashboard;
+use crate::device::{
+    Device, DeviceAction, DeviceActionResult, DeviceError, DeviceSnapshot, NodeSnapshot,
+    IDENTIFY_DEFAULT_SECS,
+};
+use crate::energy::{EnergyPeriod, EnergyTotal};
+#[cfg(feature = "openapi")]
+use crate::event::DeviceEvent;
+use crate::heater::{HeaterAction, HeaterNode};
+use crate::rules::{Rule, RuleStatus};
+use crate::schedule::{HeaterSchedule, ScheduleOverride};
 use crate::shared::SharedHandle;
+use crate::storage::{HistoryPage, HistoryQuery};
 
-#[derive(Serialize, Default)]
-struct Stats {
-    pub can: CanStats,
-    pub ctrl: ControllerStats,
+const HISTORY_DEFAULT_LIMIT: usize = 100;
+const HISTORY_MAX_LIMIT: usize = 1000;
+
+fn device_error_status(err: DeviceError) -> Status {
+    match err {
+        DeviceError::Unsupported | DeviceError::InvalidArgs => Status::BadRequest,
+        DeviceError::NotFound => Status::NotFound,
+        DeviceError::InvalidState => Status::Conflict,
+        DeviceError::CodeRequired | DeviceError::InvalidCode => Status::Forbidden,
+        DeviceError::LockedOut => Status::TooManyRequests,
+        DeviceError::Timeout => Status::GatewayTimeout,
+        DeviceError::InvalidFrame => Status::BadGateway,
+        DeviceError::Storage => Status::InternalServerError,
+    }
 }
 
-#[get("/dev_action")]
-async fn route_dev_action(shared: &State<SharedHandle>) -> Json<Response> {
-    let action = AlarmAction::PowerLights(true, true);
+/// Client of the request, authenticated once
+struct RequestIdentity(Result<Identity, AuthError>);
+
+/// Set by the guards of the mutating routes, to be logged
+struct Audited(bool);
+
+/// Subject common name of the client certificate, verified by the TLS layer
+#[cfg(feature = "tls")]
+async fn common_name<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    let certificate = request.guard::<Certificate<'r>>().await.succeeded()?;
+    certificate.subject().common_name()
+}
 
-    let alarm_handle = shared.controller_handle.query_device(DeviceNodeAction::Alarm(
-        AlarmAction::PowerLights(true, true)
-    ));
+#[cfg(not(feature = "tls"))]
+async fn common_name<'r>(_request: &'r Request<'_>) -> Option<&'r str> {
+    None
+}
 
-    // let ret = shared.controller_handle.device_handle_action(dev, &action).await;
+async fn identity<'r>(request: &'r Request<'_>) -> &'r Result<Identity, AuthError> {
+    &request
+        .local_cache_async(async {
+            let auth = request
+                .rocket()
+                .state::<AuthConfig>()
+                .expect("AuthConfig not managed");
+            let authorization = request.headers().get_one("Authorization");
+            RequestIdentity(auth.authenticate(common_name(request).await, authorization))
+        })
+        .await
+        .0
+}
+
+/// Whether a browser sent the request from another site, along with its
+/// cached credentials. Browsers tell the site of the request in
+/// `Sec-Fetch-Site`, or at least in `Origin` for the non-GET requests, the
+/// other clients sending neither. A form content type, which browsers may
+/// send cross-site without asking the server first, is refused anyway.
+fn is_cross_site(request: &Request<'_>) -> bool {
+    let headers = request.headers();
+    let form = request.content_type().is_some_and(|content_type| {
+        content_type.is_form()
+            || content_type.is_form_data()
+            || (content_type.top() == "text" && content_type.sub() == "plain")
+    });
+    let site = match headers.get_one("Sec-Fetch-Site") {
+        Some(site) => !matches!(site, "same-origin" | "none"),
+        None => headers.get_one("Origin").is_some_and(|origin| {
+            let host = origin.split_once("://").map(|(_, host)| host);
+            host.is_none() || host != headers.get_one("Host")
+        }),
+    };
+    form || site
+}
+
+async fn authorize(request: &Request<'_>, role: Role) -> Outcome<Identity, AuthError> {
+    let identity = identity(request).await;
+    let outcome = match identity {
+        _ if role > Role::Viewer && is_cross_site(request) => {
+            Outcome::Error((Status::Forbidden, AuthError::CrossSite))
+        }
+        Ok(identity) if identity.role >= role => Outcome::Success(identity.clone()),
+        Ok(_) => Outcome::Error((Status::Forbidden, AuthError::Forbidden)),
+        Err(err) => Outcome::Error((Status::Unauthorized, err.clone())),
+    };
 
-    Json(Response { id: 0 })
+    if role > Role::Viewer {
+        request.local_cache(|| Audited(true));
+        if let Outcome::Error((_, err)) = &outcome {
+            record_denied(request, identity.as_ref().ok(), err).await;
+        }
+    }
+    outcome
 }
 
+/// Record a mutating request refused by the guards in the audit log, along
+/// with the device it was for, if any
+async fn record_denied(request: &Request<'_>, identity: Option<&Identity>, err: &AuthError) {
+    let Some(shared) = request.rocket().state::<SharedHandle>() else {
+        return;
+    };
+    let mut segments = request.uri().path().segments();
+    let device_id = match segments.next() {
+        Some("devices") => segments.next().and_then(|id| id.parse().ok()),
+        _ => None,
+    };
+    let user = identity.map_or("unauthenticated", |identity| identity.name.as_str());
+
+    shared
+        .controller_handle
+        .record_audit(AuditRecord {
+            timestamp: Utc::now(),
+            origin: Origin::Http {
+                user: user.to_string(),
+            },
+            device_id,
+            action: format!("{} {}", request.method(), request.uri().path()),
+            args: Value::Null,
+            error: Some(err.to_string()),
+        })
+        .await;
+}
+
+/// Request guard of the routes open to every role
+struct Viewer;
+
+/// Request guard of the heater actions and node queries
+struct Operator(Identity);
+
+/// Request guard of the alarm security actions and node resets
+struct Admin(Identity);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Viewer {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Viewer, AuthError> {
+        authorize(request, Role::Viewer).await.map(|_| Viewer)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Operator {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Operator, AuthError> {
+        authorize(request, Role::Operator).await.map(Operator)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Admin, AuthError> {
+        authorize(request, Role::Admin).await.map(Admin)
+    }
+}
+
+/// Logs the mutating calls, denied or not, and asks the unauthenticated
+/// clients for credentials
+struct AuditLog;
+
+#[rocket::async_trait]
+impl Fairing for AuditLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Audit log",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if response.status() == Status::Unauthorized {
+            response.set_header(Header::new("WWW-Authenticate", "Basic realm=\"api\""));
+        }
+        if !request.local_cache(|| Audited(false)).0 {
+            return;
+        }
+
+        let client = match identity(request).await {
+            Ok(identity) => format!("{} ({:?})", identity.name, identity.role),
+            Err(err) => err.to_string(),
+        };
+        println!(
+            "Audit: {} {} {} -> {}",
+            client,
+            request.method(),
+            request.uri(),
+            response.status()
+        );
+    }
+}
+
+/// Turn on both lights of the first alarm node, a development shortcut
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "actions",
+        responses(
+            (status = 200, body = QueryResponse),
+            (status = 403, description = "Role too low, or cross-site request"),
+            (status = 404, description = "No alarm node"),
+            (status = 504, description = "The node did not answer"),
+        ),
+    )
+)]
+#[post("/dev_action")]
+async fn route_dev_action(
+    shared: &State<SharedHandle>,
+    auth: Operator,
+) -> Result<Json<QueryResponse>, Status> {
+    let alarm = shared
+        .controller_handle
+        .get_devices()
+        .await
+        .into_iter()
+        .find(|device| matches!(device.node, NodeSnapshot::Alarm(_)))
+        .ok_or(Status::NotFound)?;
+
+    let action = DeviceNodeAction::new(AlarmAction::PowerLights(true, true));
+    shared
+        .controller_handle
+        .query_device(alarm.id, action, Origin::from(&auth.0))
+        .await
+        .map_err(device_error_status)?;
+
+    Ok(Json(QueryResponse { id: 0 }))
+}
+
+/// Controller counters
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "controller",
+        responses(
+            (status = 200, body = ControllerStats),
+        ),
+    )
+)]
 #[get("/stats")]
-async fn route_stats(shared: &State<SharedHandle>) -> Json<ControllerStats> {
-    let stats = shared.controller_handle.get_stats().await;
+async fn route_stats(shared: &State<SharedHandle>, _auth: Viewer) -> Json<ControllerStats> {
+    let (stats, _) = shared.controller_handle.get_stats().await;
 
     Json(stats)
 }
 
-#[derive(Serialize, Default)]
-struct Response {
+/// CAN frame counters
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "controller",
+        responses(
+            (status = 200, body = CanStats),
+        ),
+    )
+)]
+#[get("/stats/can")]
+async fn route_can_stats(shared: &State<SharedHandle>, _auth: Viewer) -> Json<CanStats> {
+    let (_, can) = shared.controller_handle.get_stats().await;
+
+    Json(can)
+}
+
+/// Devices known to the controller
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "devices",
+        responses(
+            (status = 200, body = Vec<DeviceSnapshot>),
+        ),
+    )
+)]
+#[get("/devices")]
+async fn route_devices(shared: &State<SharedHandle>, _auth: Viewer) -> Json<Vec<DeviceSnapshot>> {
+    Json(shared.controller_handle.get_devices().await)
+}
+
+/// State of a device
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "devices",
+        responses(
+            (status = 200, body = DeviceSnapshot),
+            (status = 404, description = "Unknown device"),
+        ),
+    )
+)]
+#[get("/devices/<id>")]
+async fn route_device(
+    id: u32,
+    shared: &State<SharedHandle>,
+    _auth: Viewer,
+) -> Option<Json<DeviceSnapshot>> {
+    shared
+        .controller_handle
+        .get_devices()
+        .await
+        .into_iter()
+        .find(|device| device.id == id)
+        .map(Json)
+}
+
+async fn device_action(
+    shared: &SharedHandle,
+    id: u32,
+    action: DeviceAction,
+    identity: &Identity,
+) -> Result<Json<DeviceActionResult>, Status> {
+    shared
+        .controller_handle
+        .device_action(id, action, Origin::from(identity))
+        .await
+        .ok_or(Status::NotFound)?
+        .map(Json)
+        .map_err(device_error_status)
+}
+
+/// Reboot the node
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "management",
+        responses(
+            (status = 200, body = DeviceActionResult),
+            (status = 404),
+            (status = 504, description = "The node did not answer"),
+        ),
+    )
+)]
+#[post("/devices/<id>/reset")]
+async fn route_device_reset(
+    id: u32,
+    shared: &State<SharedHandle>,
+    auth: Admin,
+) -> Result<Json<DeviceActionResult>, Status> {
+    device_action(shared, id, DeviceAction::Reset, &auth.0).await
+}
+
+/// Blink the node LED for `duration` seconds
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "management",
+        responses(
+            (status = 200, body = DeviceActionResult),
+            (status = 404),
+            (status = 504, description = "The node did not answer"),
+        ),
+    )
+)]
+#[post("/devices/<id>/identify?<duration>")]
+async fn route_device_identify(
+    id: u32,
+    duration: Option<u8>,
+    shared: &State<SharedHandle>,
+    auth: Operator,
+) -> Result<Json<DeviceActionResult>, Status> {
+    let duration = duration.unwrap_or(IDENTIFY_DEFAULT_SECS);
+    device_action(shared, id, DeviceAction::Identify(duration), &auth.0).await
+}
+
+/// Check that the node answers
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "management",
+        responses(
+            (status = 200, body = DeviceActionResult),
+            (status = 404),
+            (status = 504, description = "The node did not answer"),
+        ),
+    )
+)]
+#[post("/devices/<id>/ping")]
+async fn route_device_ping(
+    id: u32,
+    shared: &State<SharedHandle>,
+    auth: Operator,
+) -> Result<Json<DeviceActionResult>, Status> {
+    device_action(shared, id, DeviceAction::Ping, &auth.0).await
+}
+
+/// Read the node firmware version
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "management",
+        responses(
+            (status = 200, body = DeviceActionResult),
+            (status = 404),
+            (status = 504, description = "The node did not answer"),
+        ),
+    )
+)]
+#[post("/devices/<id>/version")]
+async fn route_device_version(
+    id: u32,
+    shared: &State<SharedHandle>,
+    auth: Operator,
+) -> Result<Json<DeviceActionResult>, Status> {
+    device_action(shared, id, DeviceAction::ReadVersion, &auth.0).await
+}
+
+/// Actions accepted by the device, with their arguments
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "actions",
+        responses(
+            (status = 200, body = Vec<ActionSpec>),
+            (status = 404),
+        ),
+    )
+)]
+#[get("/devices/<id>/actions")]
+async fn route_device_actions(
+    id: u32,
+    shared: &State<SharedHandle>,
+    _auth: Viewer,
+) -> Option<Json<Vec<ActionSpec>>> {
+    shared.controller_handle.get_actions(id).await.map(Json)
+}
+
+/// Run an action listed by `/devices/<id>/actions`, arguments as a JSON object
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "actions",
+        request_body(content = Option<Object>, description = "Arguments, as listed by the action"),
+        responses(
+            (status = 200, description = "Done"),
+            (status = 400, description = "Unknown action or invalid arguments"),
+            (status = 403, description = "Role too low, or alarm code required or invalid"),
+            (status = 404),
+            (status = 409, description = "Not allowed in the current state"),
+            (status = 429, description = "Alarm codes locked out"),
+        ),
+    )
+)]
+#[post("/devices/<id>/actions/<name>", format = "json", data = "<args>")]
+async fn route_device_named_action(
+    id: u32,
+    name: String,
+    args: Option<Json<Value>>,
+    shared: &State<SharedHandle>,
+    auth: Operator,
+) -> Result<(), Status> {
+    let device = shared
+        .controller_handle
+        .get_devices()
+        .await
+        .into_iter()
+        .find(|device| device.id == id)
+        .ok_or(Status::NotFound)?;
+    if auth.0.role < named_action_role(&device.node, &name) {
+        shared
+            .controller_handle
+            .record_audit(AuditRecord {
+                timestamp: Utc::now(),
+                origin: Origin::from(&auth.0),
+                device_id: Some(id),
+                action: name,
+                args: Value::Null,
+                error: Some(AuthError::Forbidden.to_string()),
+            })
+            .await;
+        return Err(Status::Forbidden);
+    }
+
+    let args = args.map_or(Value::Null, |args| args.into_inner());
+    shared
+        .controller_handle
+        .named_action(id, name, args, Origin::from(&auth.0))
+        .await
+        .map_err(device_error_status)
+}
+
+/// Weekly program of a heater, null if none
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "schedule",
+        responses(
+            (status = 200, body = Option<HeaterSchedule>),
+            (status = 404),
+        ),
+    )
+)]
+#[get("/devices/<id>/schedule")]
+async fn route_get_schedule(
+    id: u32,
+    shared: &State<SharedHandle>,
+    _auth: Viewer,
+) -> Option<Json<Option<HeaterSchedule>>> {
+    shared
+        .controller_handle
+        .inspect(id, |heater: &Device<HeaterNode>| {
+            heater.specific.schedule.clone()
+        })
+        .await
+        .map(Json)
+}
+
+async fn heater_schedule_action(
+    shared: &SharedHandle,
     id: u32,
+    action: HeaterAction,
+    identity: &Identity,
+) -> Result<(), Status> {
+    // only the heaters have a schedule
+    let is_heater = shared
+        .controller_handle
+        .inspect(id, |_: &Device<HeaterNode>| ())
+        .await
+        .is_some();
+    if !is_heater {
+        return Err(Status::NotFound);
+    }
+
+    shared
+        .controller_handle
+        .query_device(id, DeviceNodeAction::new(action), Origin::from(identity))
+        .await
+        .map_err(device_error_status)
 }
 
+/// Replace the weekly program of a heater
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "schedule",
+        request_body = HeaterSchedule,
+        responses(
+            (status = 200),
+            (status = 400),
+            (status = 404),
+        ),
+    )
+)]
+#[put("/devices/<id>/schedule", format = "json", data = "<schedule>")]
+async fn route_set_schedule(
+    id: u32,
+    schedule: Json<HeaterSchedule>,
+    shared: &State<SharedHandle>,
+    auth: Operator,
+) -> Result<(), Status> {
+    let action = HeaterAction::SetSchedule(Some(schedule.into_inner()));
+    heater_schedule_action(shared, id, action, &auth.0).await
+}
+
+/// Remove the weekly program of a heater
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "schedule",
+        responses(
+            (status = 200),
+            (status = 404),
+        ),
+    )
+)]
+#[delete("/devices/<id>/schedule")]
+async fn route_delete_schedule(
+    id: u32,
+    shared: &State<SharedHandle>,
+    auth: Operator,
+) -> Result<(), Status> {
+    heater_schedule_action(shared, id, HeaterAction::SetSchedule(None), &auth.0).await
+}
+
+/// Force the state of a heater zone, until the given time or removed
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "schedule",
+        request_body = ScheduleOverride,
+        responses(
+            (status = 200),
+            (status = 400),
+            (status = 404),
+        ),
+    )
+)]
+#[put(
+    "/devices/<id>/schedule/zones/<zone>/override",
+    format = "json",
+    data = "<o>"
+)]
+async fn route_set_override(
+    id: u32,
+    zone: usize,
+    o: Json<ScheduleOverride>,
+    shared: &State<SharedHandle>,
+    auth: Operator,
+) -> Result<(), Status> {
+    let action = HeaterAction::Override(zone, Some(o.into_inner()));
+    heater_schedule_action(shared, id, action, &auth.0).await
+}
+
+/// Remove the override of a heater zone
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "schedule",
+        responses(
+            (status = 200),
+            (status = 404),
+        ),
+    )
+)]
+#[delete("/devices/<id>/schedule/zones/<zone>/override")]
+async fn route_delete_override(
+    id: u32,
+    zone: usize,
+    shared: &State<SharedHandle>,
+    auth: Operator,
+) -> Result<(), Status> {
+    heater_schedule_action(shared, id, HeaterAction::Override(zone, None), &auth.0).await
+}
+
+/// Holiday mode, forcing the state of all the heater zones
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "schedule",
+        request_body = ScheduleOverride,
+        responses(
+            (status = 200),
+            (status = 404),
+        ),
+    )
+)]
+#[put("/devices/<id>/schedule/away", format = "json", data = "<away>")]
+async fn route_set_away(
+    id: u32,
+    away: Json<ScheduleOverride>,
+    shared: &State<SharedHandle>,
+    auth: Operator,
+) -> Result<(), Status> {
+    let action = HeaterAction::Away(Some(away.into_inner()));
+    heater_schedule_action(shared, id, action, &auth.0).await
+}
+
+/// End the holiday mode of a heater
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "schedule",
+        responses(
+            (status = 200),
+            (status = 404),
+        ),
+    )
+)]
+#[delete("/devices/<id>/schedule/away")]
+async fn route_delete_away(
+    id: u32,
+    shared: &State<SharedHandle>,
+    auth: Operator,
+) -> Result<(), Status> {
+    heater_schedule_action(shared, id, HeaterAction::Away(None), &auth.0).await
+}
+
+#[derive(Responder)]
+enum EnergyResponse {
+    Json(Json<Vec<EnergyTotal>>),
+    Csv((ContentType, String)),
+}
+
+/// Heating energy totals, per `day` (default), `month` or `hour`, as JSON or
+/// CSV with `format=csv`
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "devices",
+        params(
+            ("period" = Option<String>, Query, description = "hour, day or month"),
+            ("format" = Option<String>, Query, description = "json or csv"),
+        ),
+        responses(
+            (status = 200, content((Vec<EnergyTotal> = "application/json"), (String = "text/csv"))),
+            (status = 400),
+            (status = 404),
+        ),
+    )
+)]
+#[get("/devices/<id>/energy?<period>&<format>")]
+async fn route_device_energy(
+    id: u32,
+    period: Option<&str>,
+    format: Option<&str>,
+    shared: &State<SharedHandle>,
+    _auth: Viewer,
+) -> Result<EnergyResponse, Status> {
+    let period = EnergyPeriod::parse(period.unwrap_or("day")).ok_or(Status::BadRequest)?;
+    let energy = shared
+        .controller_handle
+        .inspect(id, |heater: &Device<HeaterNode>| {
+            heater.specific.energy.clone()
+        })
+        .await
+        .ok_or(Status::NotFound)?;
+
+    match format.unwrap_or("json") {
+        "json" => Ok(EnergyResponse::Json(Json(energy.totals(period)))),
+        "csv" => Ok(EnergyResponse::Csv((
+            ContentType::CSV,
+            energy.to_csv(period),
+        ))),
+        _ => Err(Status::BadRequest),
+    }
+}
+
+fn parse_datetime(value: Option<&str>) -> Result<Option<DateTime<Utc>>, Status> {
+    value
+        .map(|v| {
+            DateTime::parse_from_rfc3339(v)
+                .map(|dt| dt.with_timezone(&Utc))
+                .map_err(|_| Status::BadRequest)
+        })
+        .transpose()
+}
+
+/// Filters and page of `/devices/<id>/history`
+#[derive(FromForm)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams), into_params(parameter_in = Query))]
+struct HistoryParams<'r> {
+    /// RFC 3339 date, included
+    from: Option<&'r str>,
+    /// RFC 3339 date, excluded
+    to: Option<&'r str>,
+    /// Event kind, e.g. `alarm_triggered`
+    kind: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+/// Recorded events of a device, paginated
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "devices",
+        params(HistoryParams),
+        responses(
+            (status = 200, body = HistoryPage),
+            (status = 400, description = "Invalid dates"),
+        ),
+    )
+)]
+#[get("/devices/<id>/history?<params..>")]
+async fn route_device_history(
+    id: u32,
+    params: HistoryParams<'_>,
+    shared: &State<SharedHandle>,
+    _auth: Viewer,
+) -> Result<Json<HistoryPage>, Status> {
+    let query = HistoryQuery {
+        device_id: id,
+        from: parse_datetime(params.from)?,
+        to: parse_datetime(params.to)?,
+        kind: params.kind,
+        offset: params.offset.unwrap_or(0),
+        limit: params
+            .limit
+            .unwrap_or(HISTORY_DEFAULT_LIMIT)
+            .min(HISTORY_MAX_LIMIT),
+    };
+
+    Ok(Json(shared.controller_handle.get_history(query).await))
+}
+
+/// Filters and page of `/audit`
+#[derive(FromForm)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams), into_params(parameter_in = Query))]
+struct AuditParams<'r> {
+    device: Option<u32>,
+    /// Origin type, e.g. `http` or `mqtt`
+    origin: Option<String>,
+    /// HTTP user
+    user: Option<String>,
+    /// Action name, e.g. `disarm`
+    action: Option<String>,
+    /// RFC 3339 date, included
+    from: Option<&'r str>,
+    /// RFC 3339 date, excluded
+    to: Option<&'r str>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+/// Actions run on the devices, by whom and with which outcome, paginated
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "controller",
+        params(AuditParams),
+        responses(
+            (status = 200, body = AuditPage),
+            (status = 400, description = "Invalid dates"),
+        ),
+    )
+)]
+#[get("/audit?<params..>")]
+async fn route_audit(
+    params: AuditParams<'_>,
+    shared: &State<SharedHandle>,
+    _auth: Admin,
+) -> Result<Json<AuditPage>, Status> {
+    let query = AuditQuery {
+        device_id: params.device,
+        origin: params.origin,
+        user: params.user,
+        action: params.action,
+        from: parse_datetime(params.from)?,
+        to: parse_datetime(params.to)?,
+        offset: params.offset.unwrap_or(0),
+        limit: params
+            .limit
+            .unwrap_or(HISTORY_DEFAULT_LIMIT)
+            .min(HISTORY_MAX_LIMIT),
+    };
+
+    Ok(Json(shared.controller_handle.get_audit(query).await))
+}
+
+/// Automation rules, with the number of times they fired
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(tag = "rules", responses((status = 200, body = Vec<RuleStatus>)))
+)]
+#[get("/rules")]
+async fn route_rules(shared: &State<SharedHandle>, _auth: Viewer) -> Json<Vec<RuleStatus>> {
+    Json(shared.controller_handle.get_rules().await)
+}
+
+/// Automation rule, by name
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "rules",
+        responses(
+            (status = 200, body = RuleStatus),
+            (status = 404),
+        ),
+    )
+)]
+#[get("/rules/<name>")]
+async fn route_rule(
+    name: &str,
+    shared: &State<SharedHandle>,
+    _auth: Viewer,
+) -> Option<Json<RuleStatus>> {
+    shared
+        .controller_handle
+        .get_rules()
+        .await
+        .into_iter()
+        .find(|status| status.rule.name == name)
+        .map(Json)
+}
+
+/// Add or replace an automation rule, named after the path
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "rules",
+        request_body = Rule,
+        responses(
+            (status = 200),
+            (status = 400, description = "Invalid rule, with the reason", body = String),
+        ),
+    )
+)]
+#[put("/rules/<name>", format = "json", data = "<rule>")]
+async fn route_set_rule(
+    name: &str,
+    rule: Json<Rule>,
+    shared: &State<SharedHandle>,
+    _auth: Admin,
+) -> Result<(), BadRequest<String>> {
+    let rule = Rule {
+        name: name.to_string(),
+        ..rule.into_inner()
+    };
+    shared
+        .controller_handle
+        .set_rule(rule)
+        .await
+        .map_err(|err| BadRequest(err.to_string()))
+}
+
+/// Remove an automation rule, configured or not
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "rules",
+        responses(
+            (status = 200),
+            (status = 404),
+        ),
+    )
+)]
+#[delete("/rules/<name>")]
+async fn route_delete_rule(
+    name: &str,
+    shared: &State<SharedHandle>,
+    _auth: Admin,
+) -> Result<(), Status> {
+    match shared.controller_handle.delete_rule(name.to_string()).await {
+        true => Ok(()),
+        false => Err(Status::NotFound),
+    }
+}
+
+/// Send a query frame to a node, answering the first byte of the reply
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "controller",
+        responses((status = 200, body = QueryResponse)),
+    )
+)]
 #[get("/query?<id>&<timeout>")]
 async fn route_query(
     id: u32,
     timeout: Option<u32>,
     shared: &State<SharedHandle>,
-) -> Json<Response> {
+    _auth: Operator,
+) -> Json<QueryResponse> {
     let id = shared.controller_handle.query(id, timeout).await;
-    Json(Response { id })
+    Json(QueryResponse { id })
+}
+
+/// Events of all the devices, or of one, as server-sent events
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        tag = "devices",
+        responses(
+            (
+                status = 200,
+                description = "`DeviceEvent` objects, as server-sent events",
+                body = DeviceEvent,
+                content_type = "text/event-stream"
+            ),
+        ),
+    )
+)]
+#[get("/events?<device>")]
+async fn route_events(
+    device: Option<u32>,
+    shared: &State<SharedHandle>,
+    mut end: Shutdown,
+    _auth: Viewer,
+) -> EventStream![] {
+    let mut events = shared.controller_handle.subscribe().await;
+    EventStream! {
+        loop {
+            let event = select! {
+                event = events.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = &mut end => break,
+            };
+            if device.is_some_and(|id| id != event.device_id) {
+                continue;
+            }
+            yield Event::json(&event);
+        }
+    }
+}
+
+/// Check that a PEM file holds a certificate, or a private key
+#[cfg(feature = "tls")]
+fn check_pem(path: &Path, key: bool) -> io::Result<()> {
+    let items = rustls_pemfile::read_all(&mut BufReader::new(File::open(path)?))?;
+    let found = items.iter().any(|item| match item {
+        Item::X509Certificate(_) => !key,
+        Item::RSAKey(_) | Item::PKCS8Key(_) | Item::ECKey(_) => key,
+        _ => false,
+    });
+    match (found, key) {
+        (true, _) => Ok(()),
+        (false, true) => Err(io::Error::new(io::ErrorKind::InvalidData, "no private key")),
+        (false, false) => Err(io::Error::new(io::ErrorKind::InvalidData, "no certificate")),
+    }
+}
+
+/// Check the TLS files before (re)starting the server, Rocket accepting files
+/// without any certificate and only reading them when started
+#[cfg(feature = "tls")]
+fn check_tls(config: &WebConfig) -> Result<(), String> {
+    let Some(tls) = &config.tls else {
+        return Ok(());
+    };
+    let client_ca = tls.client_ca.iter().map(|path| (path, false));
+    for (path, key) in [(&tls.cert, false), (&tls.key, true)]
+        .into_iter()
+        .chain(client_ca)
+    {
+        check_pem(path, key).map_err(|err| format!("{}: {}", path.display(), err))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "tls"))]
+fn check_tls(config: &WebConfig) -> Result<(), String> {
+    match config.tls {
+        Some(_) => Err("TLS needs the tls feature".to_string()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(feature = "tls")]
+fn rocket_tls_config(config: TlsConfig) -> RocketTlsConfig {
+    let tls = RocketTlsConfig::from_paths(config.cert, config.key);
+    match config.client_ca {
+        Some(ca) => tls.with_mutual(MutualTls::from_path(ca).mandatory(config.client_required)),
+        None => tls,
+    }
 }
 
-pub fn webserver(listen: String, port: u16, shared: SharedHandle) -> Rocket<Build> {
-    let config = Config {
+pub fn webserver(config: WebConfig, shared: SharedHandle) -> Rocket<Build> {
+    let rocket_config = Config {
         workers: 1,
         log_level: LogLevel::Normal,
-        port: port,
-        address: listen.parse().unwrap(),
+        port: config.port,
+        address: config.listen,
         cli_colors: false,
+        #[cfg(feature = "tls")]
+        tls: config.tls.map(rocket_tls_config),
+        // stopped along with the controller, by `run_webserver`
+        shutdown: ShutdownConfig {
+            ctrlc: false,
+            signals: HashSet::new(),
+            ..Default::default()
+        },
         ..Default::default()
     };
 
-    rocket::custom(config)
+    if !config.auth.is_enabled() {
+        let role = config.auth.anonymous.unwrap_or(Role::Viewer);
+        println!(
+            "Web API authentication disabled, no user, token or certificate configured, clients are {:?}",
+            role
+        );
+    }
+
+    let rocket = rocket::custom(rocket_config)
         .manage(shared)
-        .mount("/", routes![route_stats, route_query, route_dev_action])
+        .manage(config.auth)
+        .attach(AuditLog)
+        .mount(
+            "/",
+            routes![
+                route_stats,
+                route_can_stats,
+                route_query,
+                route_events,
+                route_dev_action,
+                route_devices,
+                route_device,
+                route_device_history,
+                route_device_energy,
+                route_device_actions,
+                route_device_named_action,
+                route_device_reset,
+                route_device_identify,
+                route_device_ping,
+                route_device_version,
+                route_get_schedule,
+                route_set_schedule,
+                route_delete_schedule,
+                route_set_override,
+                route_delete_override,
+                route_set_away,
+                route_delete_away,
+                route_audit,
+                route_rules,
+                route_rule,
+                route_set_rule,
+                route_delete_rule
+            ],
+        )
+        .mount("/", dashboard::routes());
+
+    #[cfg(feature = "openapi")]
+    let rocket = rocket.mount("/", crate::openapi::swagger_ui());
+
+    rocket
+}
+
+/// Serve the REST API until shutdown, restarting it on SIGHUP to reload the
+/// TLS certificates while the controller keeps running
+pub async fn run_webserver(
+    config: WebConfig,
+    shared: SharedHandle,
+    mut shutdown: crate::shutdown::Shutdown,
+) {
+    if let Err(err) = check_tls(&config) {
+        println!("Web server not started, {}", err);
+        return;
+    }
+
+    let mut hangup = signal(SignalKind::hangup()).expect("Failed to install SIGHUP signal handler");
+    loop {
+        let rocket = match webserver(config.clone(), shared.clone()).ignite().await {
+            Ok(rocket) => rocket,
+            Err(err) => {
+                println!("Web server failed to start: {}", err);
+                return;
+            }
+        };
+        let end = rocket.shutdown();
+        let launch = rocket.launch();
+        pin!(launch);
+
+        loop {
+            select! {
+                ret = &mut launch => {
+                    if let Err(err) = ret {
+                        println!("Web server failed: {}", err);
+                    }
+                    return;
+                }
+                _ = shutdown.recv() => {
+                    end.notify();
+                    let _ = launch.await;
+                    return;
+                }
+                _ = hangup.recv() => {}
+            }
+
+            // checked first, not to stop the server for broken files
+            match check_tls(&config) {
+                Ok(()) => break,
+                Err(err) => println!("SIGHUP received, keeping the current certificates, {}", err),
+            }
+        }
+
+        println!("SIGHUP received, restarting the web server...");
+        end.clone().notify();
+        let _ = launch.await;
+    }
 }